@@ -0,0 +1,25 @@
+CREATE TABLE saved_searches (
+  id serial PRIMARY KEY,
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  name varchar(255) NOT NULL,
+  folder varchar(255) NOT NULL,
+  query varchar(1000) NOT NULL,
+  created_at timestamp NOT NULL DEFAULT NOW(),
+  updated_at timestamp NOT NULL DEFAULT NOW(),
+  UNIQUE (user_id, name)
+);
+
+CREATE OR REPLACE FUNCTION update_saved_searches_modified_at ()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  NEW.updated_at = NOW();
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER saved_searches_modified_at_trigger
+  BEFORE UPDATE ON saved_searches
+  FOR EACH ROW
+  EXECUTE FUNCTION update_saved_searches_modified_at ();
@@ -0,0 +1,2 @@
+ALTER TABLE users
+  ADD COLUMN index_version integer NOT NULL DEFAULT 0;
@@ -0,0 +1,7 @@
+CREATE TABLE folder_aliases (
+  id serial PRIMARY KEY,
+  user_id integer NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  alias varchar(255) NOT NULL,
+  folder varchar(1000) NOT NULL,
+  UNIQUE (user_id, alias)
+);
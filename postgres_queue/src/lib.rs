@@ -286,6 +286,28 @@ pub async fn enqueue(
     Ok(row.get(0))
 }
 
+/// Fetches a task by its ID.
+pub async fn get_task(client: &Client, task_id: TaskId) -> Result<Option<Task>, TaskError> {
+    let row = client
+        .query_opt(
+            "SELECT id, name, task_data, status, run_at, interval FROM task_queue WHERE id = $1",
+            &[&task_id],
+        )
+        .await?;
+
+    Ok(row.map(|row| {
+        let interval_ms: Option<i64> = row.get(5);
+        Task {
+            id: row.get(0),
+            name: row.get(1),
+            data: row.get(2),
+            status: row.get(3),
+            run_at: row.get(4),
+            interval: interval_ms.map(|i| Duration::from_millis(i as u64)),
+        }
+    }))
+}
+
 /// Dequeues a task from the task queue.
 pub async fn dequeue(client: &mut Client) -> Result<Option<Task>, TaskError> {
     let tx = client.transaction().await?;
@@ -364,3 +386,37 @@ pub async fn fail_task(
         .await?;
     Ok(())
 }
+
+/// Merges `task_data` into a task's data, replacing any top-level keys it
+/// shares, so a running task can record its progress.
+pub async fn update_task_data(
+    client: &Client,
+    task_id: TaskId,
+    task_data: TaskData,
+) -> Result<(), TaskError> {
+    client
+        .execute(
+            "UPDATE task_queue SET updated_at = NOW(), task_data = task_data || $1::jsonb WHERE id = $2",
+            &[&task_data, &task_id],
+        )
+        .await?;
+    Ok(())
+}
+
+/// Queues a failed task to run again, clearing its error, or a task left
+/// processing by a worker that stopped, once it hasn't been updated for
+/// `stale_after`. Returns whether the task was requeued.
+pub async fn requeue_task(
+    client: &Client,
+    task_id: TaskId,
+    stale_after: Duration,
+) -> Result<bool, TaskError> {
+    let stale_before = Utc::now() - chrono::Duration::milliseconds(stale_after.as_millis() as i64);
+    let updated = client
+        .execute(
+            "UPDATE task_queue SET status = 'queued', updated_at = NOW(), run_at = NOW(), task_data = task_data - 'error' WHERE id = $1 AND (status = 'failed' OR (status = 'processing' AND updated_at < $2))",
+            &[&task_id, &stale_before],
+        )
+        .await?;
+    Ok(updated > 0)
+}
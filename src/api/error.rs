@@ -3,6 +3,8 @@ use axum::response::{IntoResponse, Response};
 use reqwest::StatusCode;
 use tracing::error;
 
+use postgres_queue::TaskError;
+
 use crate::database::DatabaseError;
 use crate::graph::GraphClientError;
 
@@ -11,6 +13,24 @@ pub enum AppError {
     Database(DatabaseError),
     Other(anyhow::Error),
     BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    TooManyRequests,
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
+    NotFound(String),
+}
+
+impl AppError {
+    /// Maps an extractor rejection to an error, keeping 413 for bodies over
+    /// the request size limit and reporting anything else as a bad request.
+    pub fn rejection(status: StatusCode, message: String) -> Self {
+        if status == StatusCode::PAYLOAD_TOO_LARGE {
+            AppError::PayloadTooLarge(message)
+        } else {
+            AppError::BadRequest(message)
+        }
+    }
 }
 
 impl From<GraphClientError> for AppError {
@@ -25,6 +45,12 @@ impl From<DatabaseError> for AppError {
     }
 }
 
+impl From<TaskError> for AppError {
+    fn from(inner: TaskError) -> Self {
+        AppError::Other(inner.into())
+    }
+}
+
 impl From<anyhow::Error> for AppError {
     fn from(inner: anyhow::Error) -> Self {
         AppError::Other(inner)
@@ -65,10 +91,29 @@ impl IntoResponse for AppError {
                     StatusCode::UNAUTHORIZED => "Unauthorized".to_string(),
                     StatusCode::FORBIDDEN => "Forbidden".to_string(),
                     StatusCode::NOT_FOUND => "Not found".to_string(),
+                    StatusCode::CONFLICT => "Conflict".to_string(),
                     _ => "An error occurred while processing the request".to_string(),
                 };
                 (status, message)
             }
+            AppError::GraphClient(GraphClientError::AttachmentTooLarge(size)) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Attachment too large: {} bytes", size),
+            ),
+            AppError::GraphClient(GraphClientError::InvalidAttachment(name)) => (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid attachment: {}", name),
+            ),
+            AppError::GraphClient(GraphClientError::FolderNotFound(name)) => {
+                (StatusCode::NOT_FOUND, format!("Folder not found: {}", name))
+            }
+            AppError::GraphClient(GraphClientError::InvalidFolderName(name)) => (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid folder name: {}", name),
+            ),
+            AppError::GraphClient(GraphClientError::InvalidCursor) => {
+                (StatusCode::BAD_REQUEST, "Invalid cursor".to_string())
+            }
             AppError::Database(err) => {
                 let message = err.to_string();
                 error!("Database error: {:?}", err);
@@ -85,6 +130,17 @@ impl IntoResponse for AppError {
                 (StatusCode::INTERNAL_SERVER_ERROR, message)
             }
             AppError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            AppError::Unauthorized(message) => (StatusCode::UNAUTHORIZED, message),
+            AppError::Forbidden(message) => (StatusCode::FORBIDDEN, message),
+            AppError::TooManyRequests => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many requests".to_string(),
+            ),
+            AppError::PayloadTooLarge(message) => (StatusCode::PAYLOAD_TOO_LARGE, message),
+            AppError::UnsupportedMediaType(message) => {
+                (StatusCode::UNSUPPORTED_MEDIA_TYPE, message)
+            }
+            AppError::NotFound(message) => (StatusCode::NOT_FOUND, message),
         };
 
         let error_response = CustomError::new(message, status);
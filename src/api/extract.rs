@@ -0,0 +1,177 @@
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    headers::{authorization::Bearer, Authorization},
+    http::request::Parts,
+    response::{IntoResponse, Response},
+    Extension, TypedHeader,
+};
+
+use crate::{
+    database::{Database, FolderAlias, User},
+    graph::{GraphClient, GraphClientError, HttpClient, Profile},
+    token::get_payload_field,
+};
+
+use super::error::AppError;
+use super::limit::TokenUse;
+
+/// Extracts a `GraphClient` authenticated with the request's bearer token,
+/// reusing the server's shared HTTP client and its network settings. The
+/// folder aliases of the token's registered user, if any, are applied.
+pub struct Graph(pub GraphClient);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Graph
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let TypedHeader(access_code) =
+            TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+        let Extension(http) = Extension::<HttpClient>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let Extension(db) = Extension::<Database>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        let access_token = access_code.token().to_owned();
+        let aliases = match get_payload_field(&access_token, "unique_name") {
+            Ok(email) => {
+                let client = db
+                    .get()
+                    .await
+                    .map_err(|err| AppError::from(err).into_response())?;
+                FolderAlias::list_by_email(&client, &email)
+                    .await
+                    .map_err(|err| AppError::from(err).into_response())?
+            }
+            Err(_) => Vec::new(),
+        };
+
+        if let Some(token_use) = parts.extensions.get::<TokenUse>() {
+            token_use.mark();
+        }
+
+        Ok(Graph(
+            GraphClient::with_http_client(http, access_token)
+                .with_folder_aliases(aliases.into_iter().map(|alias| (alias.alias, alias.folder))),
+        ))
+    }
+}
+
+/// Extracts the registered user that owns the request's bearer token.
+///
+/// The token is checked against Microsoft Graph before the user is looked up,
+/// so a forged token can't be used to reach another user's local data.
+/// Requests without a valid token are rejected with 401, and tokens for
+/// users that never registered through `/api/token` with 403.
+pub struct AuthUser(pub User);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuthUser
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let TypedHeader(access_code) =
+            TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
+                .await
+                .map_err(|_| {
+                    AppError::Unauthorized("Missing bearer token".to_string()).into_response()
+                })?;
+        let Extension(http) = Extension::<HttpClient>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let Extension(db) = Extension::<Database>::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        let email = verified_token_owner(http, access_code.token())
+            .await
+            .map_err(IntoResponse::into_response)?;
+        if let Some(token_use) = parts.extensions.get::<TokenUse>() {
+            token_use.mark();
+        }
+
+        let client = db
+            .get()
+            .await
+            .map_err(|err| AppError::from(err).into_response())?;
+        let user = User::find(&client, &email)
+            .await
+            .map_err(|err| AppError::from(err).into_response())?
+            .ok_or_else(|| {
+                AppError::Forbidden("User is not registered".to_string()).into_response()
+            })?;
+
+        Ok(AuthUser(user))
+    }
+}
+
+/// Returns the email of the user that owns an access token. The token's
+/// claims aren't verified locally, so Microsoft Graph is asked whose token it
+/// is, and a token that Graph rejects or that claims another user's name is
+/// refused with 401.
+pub async fn verified_token_owner(
+    http: HttpClient,
+    access_token: &str,
+) -> Result<String, AppError> {
+    let email = get_payload_field(access_token, "unique_name")
+        .map_err(|_| AppError::Unauthorized("Invalid token".to_string()))?;
+
+    let graph = GraphClient::with_http_client(http, access_token.to_owned());
+    let profile = graph.get_user_profile().await.map_err(|err| match err {
+        GraphClientError::Request(status) if status.is_client_error() => {
+            AppError::Unauthorized("Invalid token".to_string())
+        }
+        err => AppError::from(err),
+    })?;
+    check_token_owner(&email, &profile)?;
+
+    Ok(email)
+}
+
+/// Checks that the user a token claims to belong to is the one Graph
+/// returned the profile of.
+fn check_token_owner(email: &str, profile: &Profile) -> Result<(), AppError> {
+    if profile.user_principal_name.eq_ignore_ascii_case(email) {
+        Ok(())
+    } else {
+        Err(AppError::Unauthorized("Invalid token".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_check_token_owner() {
+        let profile: Profile = serde_json::from_value(json!({
+            "businessPhones": [],
+            "displayName": "Alice",
+            "givenName": "Alice",
+            "id": "1",
+            "mail": "alice@example.com",
+            "surname": "Smith",
+            "userPrincipalName": "Alice@example.com",
+        }))
+        .unwrap();
+
+        assert!(check_token_owner("alice@example.com", &profile).is_ok());
+        assert!(matches!(
+            check_token_owner("mallory@example.com", &profile),
+            Err(AppError::Unauthorized(_))
+        ));
+    }
+}
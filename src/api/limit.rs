@@ -0,0 +1,235 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::State,
+    http::{header, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+
+use super::error::AppError;
+
+/// Number of tokens Graph has accepted whose windows are kept.
+const VERIFIED_CAPACITY: usize = 10_000;
+
+/// Number of tokens Graph hasn't accepted yet whose windows are kept. Made-up
+/// tokens can only push each other, and other unverified tokens, out.
+const UNVERIFIED_CAPACITY: usize = 10_000;
+
+/// Start and request count of a token's current window.
+type Window = (Instant, u32);
+
+/// Windows by token digest.
+struct Windows {
+    verified: LruCache<Vec<u8>, Window>,
+    unverified: LruCache<Vec<u8>, Window>,
+}
+
+/// Fixed-window request counter keyed by access token.
+///
+/// Every token is counted in a window of its own. Tokens that Graph hasn't
+/// accepted are kept apart from verified ones, in a smaller cache that
+/// made-up tokens can fill without evicting anyone who has signed in, or
+/// using up their budget.
+///
+/// Tokens are stored as SHA-256 digests so the limiter doesn't keep
+/// credentials in memory longer than the request that carried them.
+#[derive(Clone)]
+pub struct RateLimiter {
+    limit: u32,
+    window: Duration,
+    windows: Arc<Mutex<Windows>>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter allowing `limit` requests per `window`. A limit of
+    /// zero disables rate limiting.
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self::with_capacity(limit, window, VERIFIED_CAPACITY, UNVERIFIED_CAPACITY)
+    }
+
+    fn with_capacity(limit: u32, window: Duration, verified: usize, unverified: usize) -> Self {
+        let capacity = |capacity| NonZeroUsize::new(capacity).unwrap();
+        Self {
+            limit,
+            window,
+            windows: Arc::new(Mutex::new(Windows {
+                verified: LruCache::new(capacity(verified)),
+                unverified: LruCache::new(capacity(unverified)),
+            })),
+        }
+    }
+
+    /// Records a request for `token` and returns whether it is allowed.
+    fn check(&self, token: &[u8]) -> bool {
+        self.check_at(token, Instant::now())
+    }
+
+    fn check_at(&self, token: &[u8], now: Instant) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+
+        let key = Sha256::digest(token).to_vec();
+        let mut windows = self.windows.lock().unwrap();
+        let Windows {
+            verified,
+            unverified,
+        } = &mut *windows;
+        let (start, count) = match verified.get_mut(&key) {
+            Some(window) => window,
+            None => unverified.get_or_insert_mut(key, || (now, 0)),
+        };
+        if now.duration_since(*start) >= self.window {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= self.limit
+    }
+
+    /// Moves `token`'s window to the verified tokens, once Graph has
+    /// accepted it.
+    fn verify_at(&self, token: &[u8], now: Instant) {
+        if self.limit == 0 {
+            return;
+        }
+
+        let key = Sha256::digest(token).to_vec();
+        let mut windows = self.windows.lock().unwrap();
+        if windows.verified.contains(&key) {
+            return;
+        }
+        let window = windows.unverified.pop(&key).unwrap_or((now, 0));
+        windows.verified.put(key, window);
+    }
+}
+
+/// Set by the extractors when a handler passes the request's token on to
+/// Graph, so a successful response means Graph accepted the token.
+#[derive(Clone, Default)]
+pub struct TokenUse(Arc<AtomicBool>);
+
+impl TokenUse {
+    pub fn mark(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn is_marked(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Rejects requests with 429 once their bearer token exceeds the configured
+/// rate. Requests without an `Authorization` header, such as the static
+/// frontend, are not limited.
+pub async fn rate_limit<B>(
+    State(limiter): State<RateLimiter>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let token = match request.headers().get(header::AUTHORIZATION) {
+        Some(token) => token.as_bytes().to_vec(),
+        None => return next.run(request).await,
+    };
+    if !limiter.check(&token) {
+        return AppError::TooManyRequests.into_response();
+    }
+
+    let token_use = TokenUse::default();
+    request.extensions_mut().insert(token_use.clone());
+    let response = next.run(request).await;
+    if token_use.is_marked() && response.status().is_success() {
+        limiter.verify_at(&token, Instant::now());
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+        limiter.verify_at(b"Bearer a", now);
+
+        assert!(limiter.check_at(b"Bearer a", now));
+        assert!(limiter.check_at(b"Bearer a", now + Duration::from_secs(1)));
+        assert!(!limiter.check_at(b"Bearer a", now + Duration::from_secs(2)));
+
+        // The window starts over once it has passed.
+        assert!(limiter.check_at(b"Bearer a", now + Duration::from_secs(60)));
+        assert!(limiter.check_at(b"Bearer a", now + Duration::from_secs(61)));
+        assert!(!limiter.check_at(b"Bearer a", now + Duration::from_secs(62)));
+
+        // Tokens are counted separately from each other.
+        limiter.verify_at(b"Bearer b", now);
+        assert!(limiter.check_at(b"Bearer b", now + Duration::from_secs(62)));
+    }
+
+    #[test]
+    fn test_rate_limiter_unverified() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(limiter.check_at(b"Bearer x", now));
+        assert!(limiter.check_at(b"Bearer x", now));
+        assert!(!limiter.check_at(b"Bearer x", now));
+        assert!(limiter.check_at(b"Bearer y", now));
+
+        // Verifying a token keeps the requests it has already made.
+        limiter.verify_at(b"Bearer x", now);
+        assert!(!limiter.check_at(b"Bearer x", now));
+        assert!(limiter.windows.lock().unwrap().unverified.len() == 1);
+    }
+
+    #[test]
+    fn test_rate_limiter_flood() {
+        let limiter = RateLimiter::with_capacity(2, Duration::from_secs(60), 4, 4);
+        let now = Instant::now();
+        limiter.verify_at(b"Bearer a", now);
+        assert!(limiter.check_at(b"Bearer a", now));
+
+        for i in 0..100 {
+            let token = format!("Bearer bogus-{}", i);
+            for _ in 0..3 {
+                limiter.check_at(token.as_bytes(), now);
+            }
+        }
+
+        // Neither a new token nor one already verified is held back by the
+        // made-up ones, which only evict each other.
+        assert!(limiter.check_at(b"Bearer new", now));
+        limiter.verify_at(b"Bearer new", now);
+        assert!(limiter.check_at(b"Bearer new", now));
+        assert!(limiter.check_at(b"Bearer a", now));
+        assert!(!limiter.check_at(b"Bearer a", now));
+
+        let windows = limiter.windows.lock().unwrap();
+        assert_eq!(windows.verified.len(), 2);
+        assert!(windows.unverified.len() <= 4);
+    }
+
+    #[test]
+    fn test_rate_limiter_disabled() {
+        let limiter = RateLimiter::new(0, Duration::from_secs(60));
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert!(limiter.check_at(b"Bearer a", now));
+        }
+        limiter.verify_at(b"Bearer a", now);
+        let windows = limiter.windows.lock().unwrap();
+        assert!(windows.verified.is_empty() && windows.unverified.is_empty());
+    }
+}
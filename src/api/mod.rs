@@ -1,43 +1,353 @@
 use std::net::SocketAddr;
+use std::path::{Path as FilePath, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Duration;
 
 use axum::{
+    body::{Body, StreamBody},
     debug_handler,
-    extract::{Path, Query},
+    extract::{BodyStream, DefaultBodyLimit, FromRequest, Multipart, Path, Query},
     headers::{authorization::Bearer, Authorization},
-    routing::{get, post, put},
-    Extension, Json, Router, TypedHeader,
+    http::{header, HeaderMap, Request, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{get, patch, post, put},
+    BoxError, Extension, Json, Router, TypedHeader,
 };
 use axum_error::*;
 use axum_extra::routing::SpaRouter;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use postgres_queue::{enqueue, get_task, initialize_database, requeue_task, Task, TaskId};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{fs::File, io::AsyncWriteExt};
 use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
 use tower_http::trace::TraceLayer;
-use tracing::info;
+use tracing::{error, info};
+use utoipa::{IntoParams, ToSchema};
 
 use crate::{
-    database::{Database, User},
-    graph::{Email, Folder, GraphClient, Profile},
+    database::{Database, FolderAlias, SavedSearch, User},
+    graph::{
+        subscription_expiration, Attachment, AuthResults, AutomaticReplies, BatchResult,
+        Conversation, DedupeReport, Email, EmailDelta, EmailPage, EmailUpdate, Folder, FolderDelta,
+        FolderStatus, GraphClient, HttpClient, HttpConfig, ImportFlags, ListOptions,
+        OutgoingAttachment, OutgoingMessage, Profile, SortCriterion, Subscription, Tag, Thread,
+    },
+    import::{ImportDir, ImportProgress, IMPORT_MBOX_TASK},
     index::search,
-    token::get_payload_field,
+    sanitize::sanitize_html,
+    snooze::{SNOOZED_FOLDER, UNSNOOZE_TASK},
+    spam::{pipe_to_command, SpamLearning},
+    telemetry::track_requests,
+    thumbnail::{self, ThumbnailCache, DEFAULT_SIZE, MAX_SIZE},
 };
 
 use self::error::AppError;
+use self::extract::{verified_token_owner, AuthUser, Graph};
+use self::limit::{rate_limit, RateLimiter};
+use self::openapi::{get_docs, get_openapi};
 
 mod error;
+mod extract;
+mod limit;
+mod openapi;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 struct TokenRequest {
     refresh_token: String,
 }
 
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DeleteQuery {
+    /// Delete the message instead of moving it to Deleted Items.
+    #[serde(default)]
+    permanent: bool,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct FolderRequest {
+    name: String,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListQuery {
+    /// Sort order as `field[:asc|desc]`, where the field is `date`, `from`,
+    /// `subject` or `importance`.
+    sort: Option<String>,
+}
+
+impl ListQuery {
+    fn sort(&self) -> Result<Option<SortCriterion>, AppError> {
+        parse_sort(self.sort.as_deref())
+    }
+}
+
+fn parse_sort(sort: Option<&str>) -> Result<Option<SortCriterion>, AppError> {
+    sort.map(str::parse)
+        .transpose()
+        .map_err(AppError::BadRequest)
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct MessagesQuery {
+    /// Sort order as `field[:asc|desc]`, where the field is `date`, `from`,
+    /// `subject` or `importance`.
+    sort: Option<String>,
+    /// Page size.
+    limit: Option<u32>,
+    /// Cursor returned with the previous page.
+    cursor: Option<String>,
+    /// Only list unread (`true`) or read (`false`) messages.
+    unread: Option<bool>,
+    /// Only list flagged (`true`) or unflagged (`false`) messages.
+    flagged: Option<bool>,
+    /// Search term; can't be combined with filters or sorting.
+    q: Option<String>,
+}
+
+impl MessagesQuery {
+    fn options(&self) -> Result<ListOptions, AppError> {
+        let options = ListOptions {
+            limit: self.limit,
+            sort: parse_sort(self.sort.as_deref())?,
+            unread: self.unread,
+            flagged: self.flagged,
+            search: self.q.clone(),
+        };
+        options.validate().map_err(AppError::BadRequest)?;
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct SavedSearchRequest {
+    folder: String,
+    query: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct FolderAliasRequest {
+    folder: String,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SavedSearchQuery {
+    /// Page size.
+    limit: Option<u32>,
+    /// Cursor returned with the previous page.
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ImportQuery {
+    /// Mark the imported message as read.
+    #[serde(default)]
+    read: bool,
+    /// Flag the imported message.
+    #[serde(default)]
+    flagged: bool,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct SnoozeRequest {
+    until: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DedupeQuery {
+    /// Report the duplicates without deleting them.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct SubscriptionRequest {
+    /// Folder whose messages to watch.
+    folder: String,
+    /// HTTPS endpoint that Graph will POST notifications to.
+    notification_url: String,
+    /// Comma-separated list of `created`, `updated` and `deleted`. Defaults to
+    /// all three.
+    change_type: Option<String>,
+    /// Secret sent back with every notification.
+    client_state: Option<String>,
+    /// Lifetime in minutes, capped at Graph's limit of about a week.
+    expiration_minutes: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct RenewSubscriptionRequest {
+    /// New lifetime in minutes, capped at Graph's limit of about a week.
+    expiration_minutes: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct DeltaQuery {
+    /// Cursor returned with the previous delta; omit it to start over.
+    cursor: Option<String>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ThumbnailQuery {
+    /// Width and height, in pixels, to fit the thumbnail into. Defaults to
+    /// 256 and is capped at 1024.
+    size: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct EmailQuery {
+    /// Inline `cid:` images into the body as `data:` URLs.
+    #[serde(default)]
+    inline_images: bool,
+    /// Keep remote images and CSS URLs in an HTML body, which are removed by
+    /// default so the sender can't track when the message is read.
+    #[serde(default)]
+    remote_content: bool,
+}
+
+/// A message whose HTML body has been sanitized for rendering in a browser.
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct SanitizedEmail {
+    #[serde(flatten)]
+    email: Email,
+    /// Whether remote content was removed from the body.
+    remote_content_blocked: bool,
+}
+
+impl SanitizedEmail {
+    fn new(mut email: Email, allow_remote_content: bool) -> Self {
+        let mut remote_content_blocked = false;
+        if email.body.content_type.eq_ignore_ascii_case("html") {
+            let sanitized = sanitize_html(&email.body.content, allow_remote_content);
+            email.body.content = sanitized.html;
+            remote_content_blocked = sanitized.remote_content_blocked;
+        }
+        Self {
+            email,
+            remote_content_blocked,
+        }
+    }
+}
+
+/// Result of checking a single dependency.
+#[derive(Debug, Serialize, ToSchema)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl<E: std::fmt::Display> From<Result<(), E>> for Check {
+    fn from(result: Result<(), E>) -> Self {
+        Self {
+            ok: result.is_ok(),
+            error: result.err().map(|err| err.to_string()),
+        }
+    }
+}
+
+/// Health of the server's dependencies. The Graph check is only run when the
+/// request carries an access token, and verifies that token as well.
+#[derive(Debug, Serialize, ToSchema)]
+struct Health {
+    database: Check,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    graph: Option<Check>,
+}
+
+/// Status of a background task, as reported to its owner.
+#[derive(Debug, Serialize, ToSchema)]
+struct Job {
+    id: TaskId,
+    name: String,
+    status: String,
+    error: Option<String>,
+    /// How far an mbox import has got.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    progress: Option<ImportProgress>,
+}
+
+impl From<Task> for Job {
+    fn from(task: Task) -> Self {
+        let error = task
+            .data
+            .get("error")
+            .and_then(|error| error.as_str())
+            .map(ToString::to_string);
+        let progress = task
+            .data
+            .get("progress")
+            .and_then(|progress| serde_json::from_value(progress.clone()).ok());
+        Self {
+            id: task.id,
+            name: task.name,
+            status: task.status,
+            error,
+            progress,
+        }
+    }
+}
+
+/// Time after which a job that is still processing without having saved any
+/// progress is taken to have lost its worker, and can be resumed.
+const STALLED_JOB_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Largest attachment, in bytes, that the API will download and serve or
+    /// inline into a message body.
+    pub max_attachment_size: u64,
+    /// Largest request body, in bytes, including uploads and raw imports.
+    pub max_request_size: usize,
+    /// Largest mbox file, in bytes, accepted for import. Uploads are written
+    /// to disk, so this can be far larger than `max_request_size`.
+    pub max_import_size: u64,
+    /// Requests per minute allowed for each access token, or 0 for no limit.
+    pub rate_limit: u32,
+}
+
 pub struct Server {
     addr: SocketAddr,
     database_url: String,
+    limits: Limits,
+    http: HttpConfig,
+    spam: SpamLearning,
+    import_dir: PathBuf,
 }
 
 impl Server {
-    pub fn new(addr: SocketAddr, database_url: String) -> Self {
-        Self { addr, database_url }
+    pub fn new(
+        addr: SocketAddr,
+        database_url: String,
+        limits: Limits,
+        http: HttpConfig,
+        spam: SpamLearning,
+        import_dir: PathBuf,
+    ) -> Self {
+        Self {
+            addr,
+            database_url,
+            limits,
+            http,
+            spam,
+            import_dir,
+        }
     }
 
     pub async fn start(&self) -> anyhow::Result<()> {
@@ -46,28 +356,122 @@ impl Server {
 
         info!("Running migrations...");
         db.migrate().await?;
+        initialize_database(db.pool()).await?;
+
+        let http = self.http.build_client()?;
+        let imports = ImportDir::create(&self.import_dir)?;
 
         info!("Listening on {}", self.addr);
         Ok(axum::Server::bind(&self.addr)
-            .serve(self.routes(db).into_make_service())
+            .serve(self.routes(db, http, imports).into_make_service())
             .await?)
     }
 
-    pub fn routes(&self, db: Database) -> Router {
+    pub fn routes(&self, db: Database, http: HttpClient, imports: ImportDir) -> Router {
         Router::new()
+            .route("/api/openapi.json", get(get_openapi))
+            .route("/api/docs", get(get_docs))
             .route("/api/me", get(get_profile))
+            .route("/api/health", get(get_health))
             .route("/api/token", post(post_token))
             .route("/api/search", get(get_search))
+            .route("/api/searches", get(get_saved_searches))
+            .route(
+                "/api/searches/:name",
+                put(put_saved_search).delete(delete_saved_search),
+            )
+            .route(
+                "/api/searches/:name/messages",
+                get(get_saved_search_messages),
+            )
+            .route("/api/folder-aliases", get(get_folder_aliases))
+            .route(
+                "/api/folder-aliases/:alias",
+                put(put_folder_alias).delete(delete_folder_alias),
+            )
+            .route("/api/jobs/index", post(post_index_job))
+            .route("/api/jobs/:id", get(get_job))
+            .route("/api/jobs/:id/resume", post(post_resume_job))
             .route("/api/emails", get(get_emails))
             .route("/api/emails/move/:folder", put(put_bulk_move))
-            .route("/api/emails/:id", get(get_email))
+            .route(
+                "/api/emails/:id",
+                get(get_email).patch(patch_email).delete(delete_email),
+            )
+            .route("/api/emails/:id/raw", get(get_email_raw))
+            .route("/api/emails/:id/conversation", get(get_email_conversation))
+            .route("/api/emails/:id/auth-results", get(get_email_auth_results))
+            .route("/api/emails/:id/attachments", get(get_email_attachments))
+            .route(
+                "/api/emails/:id/attachments/:attachment_id",
+                get(get_attachment),
+            )
+            .route(
+                "/api/emails/:id/attachments/:attachment_id/thumbnail",
+                get(get_attachment_thumbnail),
+            )
             .route("/api/emails/:id/move/:folder", put(put_move))
+            .route(
+                "/api/emails/:id/tags/:tag",
+                put(put_email_tag).delete(delete_email_tag),
+            )
             .route("/api/emails/:id/archive", put(put_archive))
+            .route(
+                "/api/emails/:id/forward-as-attachment",
+                post(post_forward_as_attachment),
+            )
+            .route("/api/emails/:id/snooze", put(put_snooze))
             .route("/api/emails/:id/spam", put(put_mark_spam))
-            .route("/api/folders", get(get_folders))
+            .route("/api/emails/:id/ham", put(put_mark_ham))
+            .route(
+                "/api/subscriptions",
+                get(get_subscriptions).post(post_subscription),
+            )
+            .route(
+                "/api/subscriptions/:id",
+                patch(patch_subscription).delete(delete_subscription),
+            )
+            .route("/api/tags", get(get_tags))
+            .route("/api/vacation", get(get_vacation).put(put_vacation))
+            .route("/api/messages/send", post(post_send_message))
+            .route("/api/drafts", get(get_drafts).post(post_draft))
+            .route("/api/drafts/:id", patch(patch_draft).delete(delete_draft))
+            .route("/api/drafts/:id/send", post(post_send_draft))
+            .route("/api/folders", get(get_folders).post(post_folder))
+            .route(
+                "/api/folders/:folder",
+                patch(patch_folder).delete(delete_folder),
+            )
+            .route("/api/folders/status", get(get_folders_status))
+            .route("/api/folders/delta", get(get_folders_delta))
+            .route("/api/folders/:folder/status", get(get_folder_status))
+            .route(
+                "/api/folders/:folder/messages",
+                get(get_folder_messages).patch(patch_folder_messages),
+            )
+            .route("/api/folders/:folder/import", post(post_folder_import))
+            .route("/api/folders/:folder/delta", get(get_folder_delta))
+            .route("/api/folders/:folder/dedupe", post(post_folder_dedupe))
+            .route("/api/folders/:folder/export", get(get_folder_export))
+            .route(
+                "/api/folders/:folder/import/mbox",
+                post(post_folder_import_mbox),
+            )
             .route("/api/:folder/emails", get(get_folder_emails))
+            .route("/api/:folder/threads", get(get_folder_threads))
+            .route_layer(middleware::from_fn(track_requests))
             .merge(SpaRouter::new("/", "public").index_file("index.html"))
+            .layer(middleware::from_fn_with_state(
+                RateLimiter::new(self.limits.rate_limit, Duration::from_secs(60)),
+                rate_limit,
+            ))
+            .layer(DefaultBodyLimit::max(self.limits.max_request_size))
             .layer(Extension(db))
+            .layer(Extension(http))
+            .layer(Extension(self.limits.clone()))
+            .layer(Extension(self.spam.clone()))
+            .layer(Extension(ThumbnailCache::default()))
+            .layer(Extension(imports))
             .layer(
                 CorsLayer::new()
                     .allow_origin(AllowOrigin::any())
@@ -78,21 +482,76 @@ impl Server {
     }
 }
 
-async fn get_profile(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
-) -> Result<Json<Profile>, AppError> {
-    let client = GraphClient::new(access_code.token().to_owned());
+#[utoipa::path(
+    get,
+    path = "/api/me",
+    tag = "profile",
+    responses(
+        (status = 200, description = "The signed-in user", body = Profile)
+    )
+)]
+async fn get_profile(Graph(client): Graph) -> Result<Json<Profile>, AppError> {
     Ok(Json(client.get_user_profile().await?))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/health",
+    tag = "health",
+    security((), ("bearer" = [])),
+    responses(
+        (status = 200, description = "All dependencies are healthy", body = Health),
+        (status = 503, description = "A dependency is unhealthy", body = Health)
+    )
+)]
+async fn get_health(
+    access_code: Option<TypedHeader<Authorization<Bearer>>>,
+    Extension(db): Extension<Database>,
+    Extension(http): Extension<HttpClient>,
+) -> (StatusCode, Json<Health>) {
+    let database: Check = match db.get().await {
+        Ok(client) => client.simple_query("SELECT 1").await.map(|_| ()).into(),
+        Err(err) => Err::<(), _>(err).into(),
+    };
+    let graph: Option<Check> = match access_code {
+        Some(TypedHeader(access_code)) => {
+            let client = GraphClient::with_http_client(http, access_code.token().to_owned());
+            Some(client.get_user_profile().await.map(|_| ()).into())
+        }
+        None => None,
+    };
+
+    let healthy = database.ok && graph.iter().all(|graph| graph.ok);
+    let status = if healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(Health { database, graph }))
+}
+
+/// Registers the owner of the bearer token, storing their tokens for the
+/// background jobs. The token is verified with Graph first, so a forged token
+/// can't overwrite another user's stored tokens.
+#[utoipa::path(
+    post,
+    path = "/api/token",
+    tag = "auth",
+    request_body = TokenRequest,
+    responses(
+        (status = 200, description = "The registered user", body = User),
+        (status = 401, description = "The access token is invalid", body = ErrorMessage)
+    )
+)]
 #[debug_handler]
 async fn post_token(
     TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
     Extension(db): Extension<Database>,
+    Extension(http): Extension<HttpClient>,
     Json(data): Json<TokenRequest>,
 ) -> Result<Json<User>, AppError> {
     let access_token = access_code.token().to_owned();
-    let email = get_payload_field(&access_token, "unique_name")?;
+    let email = verified_token_owner(http, &access_token).await?;
     let client = db.get().await?;
 
     // TODO: do we need expiration time?
@@ -102,15 +561,20 @@ async fn post_token(
     Ok(Json(user))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/search",
+    tag = "search",
+    params(("q" = String, Query, description = "Search term")),
+    responses(
+        (status = 200, description = "Indexed messages matching the term", body = [Email]),
+        (status = 400, description = "Invalid request", body = ErrorMessage)
+    )
+)]
 async fn get_search(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
+    AuthUser(user): AuthUser,
     Query(query): Query<serde_json::Value>,
 ) -> Result<Json<Vec<Email>>, AppError> {
-    let access_token = access_code.token().to_owned();
-    let email = get_payload_field(&access_token, "unique_name")?;
-    info!("email: {}", email);
-
-    // TODO: check profile email against token email for security
     info!("Searching for {query:?}...");
 
     let query = query.as_object().ok_or(AppError::BadRequest(
@@ -126,48 +590,1356 @@ async fn get_search(
         .ok_or(AppError::BadRequest(
             "invalid search term, use q=<term> where term must be a string".to_string(),
         ))?;
-    Ok(Json(search(&email, term).await?))
+    Ok(Json(search(&user.email, term).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/searches",
+    tag = "searches",
+    responses(
+        (status = 200, description = "The user's saved searches", body = [SavedSearch])
+    )
+)]
+async fn get_saved_searches(
+    AuthUser(user): AuthUser,
+    Extension(db): Extension<Database>,
+) -> Result<Json<Vec<SavedSearch>>, AppError> {
+    let client = db.get().await?;
+    Ok(Json(SavedSearch::list(&client, user_id(&user)?).await?))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/searches/{name}",
+    tag = "searches",
+    params(("name" = String, Path, description = "Saved search name")),
+    request_body = SavedSearchRequest,
+    responses(
+        (status = 200, description = "The saved search", body = SavedSearch),
+        (status = 400, description = "Invalid request", body = ErrorMessage)
+    )
+)]
+async fn put_saved_search(
+    AuthUser(user): AuthUser,
+    Extension(db): Extension<Database>,
+    Path(name): Path<String>,
+    Json(request): Json<SavedSearchRequest>,
+) -> Result<Json<SavedSearch>, AppError> {
+    if request.query.trim().is_empty() {
+        return Err(AppError::BadRequest("query must not be empty".to_string()));
+    }
+    let search = SavedSearch {
+        name,
+        folder: request.folder,
+        query: request.query,
+    };
+    let client = db.get().await?;
+    search.upsert(&client, user_id(&user)?).await?;
+    Ok(Json(search))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/searches/{name}",
+    tag = "searches",
+    params(("name" = String, Path, description = "Saved search name")),
+    responses(
+        (status = 204, description = "The saved search was deleted"),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn delete_saved_search(
+    AuthUser(user): AuthUser,
+    Extension(db): Extension<Database>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let client = db.get().await?;
+    if SavedSearch::delete(&client, user_id(&user)?, &name).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound(format!(
+            "Saved search not found: {}",
+            name
+        )))
+    }
+}
+
+/// Lists one page of the messages matching a saved search, like a folder.
+#[utoipa::path(
+    get,
+    path = "/api/searches/{name}/messages",
+    tag = "searches",
+    params(("name" = String, Path, description = "Saved search name"), SavedSearchQuery),
+    responses(
+        (status = 200, description = "One page of matching messages", body = EmailPage),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn get_saved_search_messages(
+    AuthUser(user): AuthUser,
+    Graph(mut client): Graph,
+    Extension(db): Extension<Database>,
+    Path(name): Path<String>,
+    Query(query): Query<SavedSearchQuery>,
+) -> Result<Json<EmailPage>, AppError> {
+    let db_client = db.get().await?;
+    let search = SavedSearch::find(&db_client, user_id(&user)?, &name)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Saved search not found: {}", name)))?;
+    let options = ListOptions {
+        limit: query.limit,
+        search: Some(search.query),
+        ..Default::default()
+    };
+    Ok(Json(
+        client
+            .get_folder_emails_page(&search.folder, &options, query.cursor.as_deref())
+            .await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/folder-aliases",
+    tag = "folders",
+    responses(
+        (status = 200, description = "The user's folder aliases", body = [FolderAlias])
+    )
+)]
+async fn get_folder_aliases(
+    AuthUser(user): AuthUser,
+    Extension(db): Extension<Database>,
+) -> Result<Json<Vec<FolderAlias>>, AppError> {
+    let client = db.get().await?;
+    Ok(Json(FolderAlias::list(&client, user_id(&user)?).await?))
 }
 
+/// Points a logical folder name, such as `sent` or `trash`, at a folder path
+/// in the user's mailbox. Aliases are matched case-insensitively against the
+/// first segment of folder paths.
+#[utoipa::path(
+    put,
+    path = "/api/folder-aliases/{alias}",
+    tag = "folders",
+    params(("alias" = String, Path, description = "Logical folder name")),
+    request_body = FolderAliasRequest,
+    responses(
+        (status = 200, description = "The folder alias", body = FolderAlias),
+        (status = 400, description = "Invalid request", body = ErrorMessage)
+    )
+)]
+async fn put_folder_alias(
+    AuthUser(user): AuthUser,
+    Extension(db): Extension<Database>,
+    Path(alias): Path<String>,
+    Json(request): Json<FolderAliasRequest>,
+) -> Result<Json<FolderAlias>, AppError> {
+    if alias.trim().is_empty() || alias.contains('/') {
+        return Err(AppError::BadRequest(format!(
+            "Invalid folder alias: {}",
+            alias
+        )));
+    }
+    if request.folder.trim_matches('/').is_empty() {
+        return Err(AppError::BadRequest("folder must not be empty".to_string()));
+    }
+    let alias = FolderAlias {
+        alias: alias.to_lowercase(),
+        folder: request.folder,
+    };
+    let client = db.get().await?;
+    alias.upsert(&client, user_id(&user)?).await?;
+    Ok(Json(alias))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/folder-aliases/{alias}",
+    tag = "folders",
+    params(("alias" = String, Path, description = "Logical folder name")),
+    responses(
+        (status = 204, description = "The folder alias was deleted"),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn delete_folder_alias(
+    AuthUser(user): AuthUser,
+    Extension(db): Extension<Database>,
+    Path(alias): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let client = db.get().await?;
+    if FolderAlias::delete(&client, user_id(&user)?, &alias.to_lowercase()).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound(format!(
+            "Folder alias not found: {}",
+            alias
+        )))
+    }
+}
+
+fn user_id(user: &User) -> Result<i32, AppError> {
+    user.id
+        .ok_or_else(|| AppError::Other(anyhow::anyhow!("user {} has no id", user.email)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/jobs/index",
+    tag = "jobs",
+    responses(
+        (status = 200, description = "The queued indexing job", body = Job)
+    )
+)]
+async fn post_index_job(
+    AuthUser(user): AuthUser,
+    Extension(db): Extension<Database>,
+) -> Result<Json<Job>, AppError> {
+    let client = db.get().await?;
+    let data = json!({ "user_email": user.email });
+    let id = enqueue(&client, "full_index", data, Utc::now(), None).await?;
+    let task = get_task(&client, id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Job not found: {}", id)))?;
+    Ok(Json(task.into()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/jobs/{id}",
+    tag = "jobs",
+    params(("id" = i32, Path, description = "Job id")),
+    responses(
+        (status = 200, description = "The job", body = Job),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn get_job(
+    AuthUser(user): AuthUser,
+    Extension(db): Extension<Database>,
+    Path(id): Path<TaskId>,
+) -> Result<Json<Job>, AppError> {
+    let client = db.get().await?;
+    let task = get_task(&client, id)
+        .await?
+        .filter(|task| task.data["user_email"].as_str() == Some(user.email.as_str()))
+        .ok_or_else(|| AppError::NotFound(format!("Job not found: {}", id)))?;
+    Ok(Json(task.into()))
+}
+
+/// Runs a failed job again, or one whose worker stopped without finishing
+/// it. An mbox import resumes after the last message it saved progress for.
+#[utoipa::path(
+    post,
+    path = "/api/jobs/{id}/resume",
+    tag = "jobs",
+    params(("id" = i32, Path, description = "Job id")),
+    responses(
+        (status = 200, description = "The requeued job", body = Job),
+        (status = 400, description = "The job is still running or has completed", body = ErrorMessage),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn post_resume_job(
+    AuthUser(user): AuthUser,
+    Extension(db): Extension<Database>,
+    Path(id): Path<TaskId>,
+) -> Result<Json<Job>, AppError> {
+    let client = db.get().await?;
+    get_task(&client, id)
+        .await?
+        .filter(|task| task.data["user_email"].as_str() == Some(user.email.as_str()))
+        .ok_or_else(|| AppError::NotFound(format!("Job not found: {}", id)))?;
+
+    if !requeue_task(&client, id, STALLED_JOB_TIMEOUT).await? {
+        return Err(AppError::BadRequest(format!(
+            "Job {} is still running or has completed",
+            id
+        )));
+    }
+    let task = get_task(&client, id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Job not found: {}", id)))?;
+    Ok(Json(task.into()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/emails",
+    tag = "emails",
+    params(ListQuery),
+    responses(
+        (status = 200, description = "Messages in the mailbox", body = [Email]),
+        (status = 400, description = "Invalid request", body = ErrorMessage)
+    )
+)]
 async fn get_emails(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
+    Graph(client): Graph,
+    Query(query): Query<ListQuery>,
 ) -> Result<Json<Vec<Email>>, AppError> {
-    let client = GraphClient::new(access_code.token().to_owned());
-    Ok(Json(client.get_user_emails().await?))
+    Ok(Json(client.get_user_emails(query.sort()?).await?))
 }
 
-async fn get_folders(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
-) -> Result<Json<Vec<Folder>>, AppError> {
-    let client = GraphClient::new(access_code.token().to_owned());
+#[utoipa::path(
+    get,
+    path = "/api/subscriptions",
+    tag = "subscriptions",
+    responses(
+        (status = 200, description = "The user's change notification subscriptions", body = [Subscription])
+    )
+)]
+async fn get_subscriptions(Graph(client): Graph) -> Result<Json<Vec<Subscription>>, AppError> {
+    Ok(Json(client.list_subscriptions().await?))
+}
+
+/// Subscribes a webhook to changes of the messages in a folder. Graph checks
+/// the webhook by sending it a `validationToken` query parameter, which it
+/// must echo back as `text/plain` within ten seconds.
+#[utoipa::path(
+    post,
+    path = "/api/subscriptions",
+    tag = "subscriptions",
+    request_body = SubscriptionRequest,
+    responses(
+        (status = 200, description = "The new subscription", body = Subscription),
+        (status = 400, description = "Invalid request", body = ErrorMessage),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn post_subscription(
+    Graph(mut client): Graph,
+    Json(request): Json<SubscriptionRequest>,
+) -> Result<Json<Subscription>, AppError> {
+    if !request.notification_url.starts_with("https://") {
+        return Err(AppError::BadRequest(
+            "notification_url must be an https URL".to_string(),
+        ));
+    }
+    let change_type = request
+        .change_type
+        .unwrap_or_else(|| "created,updated,deleted".to_string());
+    if let Some(invalid) = change_type
+        .split(',')
+        .find(|change| !matches!(*change, "created" | "updated" | "deleted"))
+    {
+        return Err(AppError::BadRequest(format!(
+            "Invalid change type: {}",
+            invalid
+        )));
+    }
+
+    let expiration = subscription_expiration(Utc::now(), request.expiration_minutes);
+    Ok(Json(
+        client
+            .create_subscription(
+                &request.folder,
+                &request.notification_url,
+                &change_type,
+                request.client_state.as_deref(),
+                expiration,
+            )
+            .await?,
+    ))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/subscriptions/{id}",
+    tag = "subscriptions",
+    params(("id" = String, Path, description = "Subscription id")),
+    request_body = RenewSubscriptionRequest,
+    responses(
+        (status = 200, description = "The renewed subscription", body = Subscription),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn patch_subscription(
+    Graph(client): Graph,
+    Path(id): Path<String>,
+    Json(request): Json<RenewSubscriptionRequest>,
+) -> Result<Json<Subscription>, AppError> {
+    let expiration = subscription_expiration(Utc::now(), request.expiration_minutes);
+    Ok(Json(client.renew_subscription(&id, expiration).await?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/subscriptions/{id}",
+    tag = "subscriptions",
+    params(("id" = String, Path, description = "Subscription id")),
+    responses(
+        (status = 204, description = "The subscription was deleted"),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn delete_subscription(
+    Graph(client): Graph,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    client.delete_subscription(&id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/tags",
+    tag = "tags",
+    responses(
+        (status = 200, description = "The user's tags", body = [Tag])
+    )
+)]
+async fn get_tags(Graph(client): Graph) -> Result<Json<Vec<Tag>>, AppError> {
+    Ok(Json(client.list_tags().await?))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/emails/{id}/tags/{tag}",
+    tag = "tags",
+    params(("id" = String, Path, description = "Message id"), ("tag" = String, Path, description = "Tag name")),
+    responses(
+        (status = 200, description = "The tagged message", body = Email)
+    )
+)]
+async fn put_email_tag(
+    Graph(client): Graph,
+    Path((id, tag)): Path<(String, String)>,
+) -> Result<Json<Email>, AppError> {
+    Ok(Json(client.add_tag(&id, &tag).await?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/emails/{id}/tags/{tag}",
+    tag = "tags",
+    params(("id" = String, Path, description = "Message id"), ("tag" = String, Path, description = "Tag name")),
+    responses(
+        (status = 200, description = "The untagged message", body = Email)
+    )
+)]
+async fn delete_email_tag(
+    Graph(client): Graph,
+    Path((id, tag)): Path<(String, String)>,
+) -> Result<Json<Email>, AppError> {
+    Ok(Json(client.remove_tag(&id, &tag).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/vacation",
+    tag = "vacation",
+    responses(
+        (status = 200, description = "The automatic replies settings", body = AutomaticReplies)
+    )
+)]
+async fn get_vacation(Graph(client): Graph) -> Result<Json<AutomaticReplies>, AppError> {
+    Ok(Json(client.get_automatic_replies().await?))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/vacation",
+    tag = "vacation",
+    request_body = AutomaticReplies,
+    responses(
+        (status = 200, description = "The updated settings", body = AutomaticReplies)
+    )
+)]
+async fn put_vacation(
+    Graph(client): Graph,
+    Json(settings): Json<AutomaticReplies>,
+) -> Result<Json<AutomaticReplies>, AppError> {
+    Ok(Json(client.set_automatic_replies(&settings).await?))
+}
+
+/// Sends a message given either as JSON, or as `multipart/form-data` with the
+/// JSON in a `message` field and each file field added as an attachment.
+#[utoipa::path(
+    post,
+    path = "/api/messages/send",
+    tag = "messages",
+    request_body(content = OutgoingMessage, description = "The message as JSON, or as `multipart/form-data` with the JSON in a `message` field and files as attachments"),
+    responses(
+        (status = 202, description = "The message was sent"),
+        (status = 400, description = "Invalid request", body = ErrorMessage),
+        (status = 413, description = "An attachment is too large", body = ErrorMessage)
+    )
+)]
+async fn post_send_message(
+    Graph(client): Graph,
+    request: Request<Body>,
+) -> Result<StatusCode, AppError> {
+    let content_type = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let is_multipart =
+        matches!(content_type, Some(value) if value.starts_with("multipart/form-data"));
+
+    let message = if is_multipart {
+        let multipart = Multipart::from_request(request, &())
+            .await
+            .map_err(|err| AppError::rejection(err.status(), err.body_text()))?;
+        read_multipart_message(multipart).await?
+    } else {
+        let Json(message) = Json::<OutgoingMessage>::from_request(request, &())
+            .await
+            .map_err(|err| AppError::rejection(err.status(), err.body_text()))?;
+        message
+    };
+
+    client.send_mail(&message).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn read_multipart_message(mut multipart: Multipart) -> Result<OutgoingMessage, AppError> {
+    let mut message = OutgoingMessage::default();
+    let mut attachments = Vec::new();
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| AppError::rejection(err.status(), err.body_text()))?
+    {
+        let name = field.name().map(ToString::to_string);
+        let file_name = field.file_name().map(ToString::to_string);
+        let content_type = field.content_type().map(ToString::to_string);
+        let bytes = field
+            .bytes()
+            .await
+            .map_err(|err| AppError::rejection(err.status(), err.body_text()))?;
+
+        if let Some(file_name) = file_name {
+            attachments.push(OutgoingAttachment::new(file_name, content_type, &bytes));
+        } else if name.as_deref() == Some("message") {
+            message = serde_json::from_slice(&bytes)
+                .map_err(|err| AppError::BadRequest(format!("invalid message: {}", err)))?;
+        }
+    }
+
+    message
+        .attachments
+        .get_or_insert_with(Vec::new)
+        .extend(attachments);
+    Ok(message)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/drafts",
+    tag = "drafts",
+    responses(
+        (status = 200, description = "The user's drafts", body = [Email])
+    )
+)]
+async fn get_drafts(Graph(client): Graph) -> Result<Json<Vec<Email>>, AppError> {
+    Ok(Json(client.get_drafts().await?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/drafts",
+    tag = "drafts",
+    request_body = OutgoingMessage,
+    responses(
+        (status = 200, description = "The new draft", body = Email)
+    )
+)]
+async fn post_draft(
+    Graph(client): Graph,
+    Json(message): Json<OutgoingMessage>,
+) -> Result<Json<Email>, AppError> {
+    Ok(Json(client.create_draft(&message).await?))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/drafts/{id}",
+    tag = "drafts",
+    params(("id" = String, Path, description = "Message id")),
+    request_body = OutgoingMessage,
+    responses(
+        (status = 200, description = "The updated draft", body = Email),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn patch_draft(
+    Graph(client): Graph,
+    Path(id): Path<String>,
+    Json(message): Json<OutgoingMessage>,
+) -> Result<Json<Email>, AppError> {
+    Ok(Json(client.update_draft(&id, &message).await?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/drafts/{id}",
+    tag = "drafts",
+    params(("id" = String, Path, description = "Message id")),
+    responses(
+        (status = 204, description = "The draft was deleted"),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn delete_draft(
+    Graph(client): Graph,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    client.delete_draft(&id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/drafts/{id}/send",
+    tag = "drafts",
+    params(("id" = String, Path, description = "Message id")),
+    responses(
+        (status = 202, description = "The draft was sent"),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn post_send_draft(
+    Graph(client): Graph,
+    Path(id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    client.send_draft(&id).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/folders",
+    tag = "folders",
+    responses(
+        (status = 200, description = "The user's folders", body = [Folder])
+    )
+)]
+async fn get_folders(Graph(client): Graph) -> Result<Json<Vec<Folder>>, AppError> {
     Ok(Json(client.get_user_folders().await?))
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/folders",
+    tag = "folders",
+    request_body = FolderRequest,
+    responses(
+        (status = 200, description = "The folder, created if missing", body = Folder),
+        (status = 400, description = "Invalid request", body = ErrorMessage)
+    )
+)]
+async fn post_folder(
+    Graph(client): Graph,
+    Json(data): Json<FolderRequest>,
+) -> Result<Json<Folder>, AppError> {
+    Ok(Json(client.create_folder(&data.name).await?))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/folders/{folder}",
+    tag = "folders",
+    params(("folder" = String, Path, description = "Folder name, path or alias")),
+    request_body = FolderRequest,
+    responses(
+        (status = 200, description = "The renamed folder", body = Folder),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn patch_folder(
+    Graph(mut client): Graph,
+    Path(folder): Path<String>,
+    Json(data): Json<FolderRequest>,
+) -> Result<Json<Folder>, AppError> {
+    Ok(Json(client.rename_folder(&folder, &data.name).await?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/folders/{folder}",
+    tag = "folders",
+    params(("folder" = String, Path, description = "Folder name, path or alias")),
+    responses(
+        (status = 204, description = "The folder was deleted"),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn delete_folder(
+    Graph(mut client): Graph,
+    Path(folder): Path<String>,
+) -> Result<StatusCode, AppError> {
+    client.delete_folder(&folder).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Imports a raw RFC 822 message into a folder as a received message,
+/// streaming the request body on to Graph.
+#[utoipa::path(
+    post,
+    path = "/api/folders/{folder}/import",
+    tag = "folders",
+    params(("folder" = String, Path, description = "Folder name, path or alias"), ImportQuery),
+    request_body(content = String, description = "The raw message", content_type = "message/rfc822"),
+    responses(
+        (status = 200, description = "The imported message", body = Email),
+        (status = 404, description = "Not found", body = ErrorMessage),
+        (status = 413, description = "The message is over the request size limit", body = ErrorMessage)
+    )
+)]
+async fn post_folder_import(
+    Graph(mut client): Graph,
+    Extension(limits): Extension<Limits>,
+    Path(folder): Path<String>,
+    Query(query): Query<ImportQuery>,
+    headers: HeaderMap,
+    mime: BodyStream,
+) -> Result<Json<Email>, AppError> {
+    let max_size = limits.max_request_size as u64;
+    let (mime, exceeded) = limit_body(&headers, mime, max_size)?;
+    let flags = ImportFlags {
+        is_read: query.read,
+        is_flagged: query.flagged,
+    };
+    match client.import_email(&folder, mime, flags).await {
+        Err(_) if exceeded.load(Ordering::Relaxed) => Err(body_too_large(max_size)),
+        result => Ok(Json(result?)),
+    }
+}
+
+/// Caps a streamed request body at `max_size` bytes, since `DefaultBodyLimit`
+/// doesn't apply to `BodyStream`. A body declaring a larger `Content-Length`
+/// is rejected up front; any other fails once it grows over the limit and
+/// sets the returned flag, so the handler can tell that apart from whatever
+/// error its consumer reports.
+fn limit_body(
+    headers: &HeaderMap,
+    body: BodyStream,
+    max_size: u64,
+) -> Result<
+    (
+        impl Stream<Item = Result<Bytes, BoxError>> + Send + Sync + 'static,
+        Arc<AtomicBool>,
+    ),
+    AppError,
+> {
+    let length = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok()?.parse::<u64>().ok());
+    if length.is_some_and(|length| length > max_size) {
+        return Err(body_too_large(max_size));
+    }
+
+    let exceeded = Arc::new(AtomicBool::new(false));
+    let flag = exceeded.clone();
+    let mut size = 0;
+    let body = body.map(move |chunk| {
+        let chunk = chunk?;
+        size += chunk.len() as u64;
+        if size > max_size {
+            flag.store(true, Ordering::Relaxed);
+            return Err("request body is over the size limit".into());
+        }
+        Ok(chunk)
+    });
+    Ok((body, exceeded))
+}
+
+fn body_too_large(max_size: u64) -> AppError {
+    AppError::PayloadTooLarge(format!("Request body is over the {} byte limit", max_size))
+}
+
+/// Uploads an mbox file, such as a Google Takeout export, and queues a job
+/// that imports its messages into a folder with their read and flagged state.
+/// The job reports its progress and can be resumed if it stops.
+#[utoipa::path(
+    post,
+    path = "/api/folders/{folder}/import/mbox",
+    tag = "folders",
+    params(("folder" = String, Path, description = "Folder name, path or alias")),
+    request_body(content = String, description = "An mbox file", content_type = "application/mbox"),
+    responses(
+        (status = 200, description = "The queued import job", body = Job),
+        (status = 404, description = "Not found", body = ErrorMessage),
+        (status = 413, description = "The file is over the import size limit", body = ErrorMessage)
+    )
+)]
+async fn post_folder_import_mbox(
+    AuthUser(user): AuthUser,
+    Graph(mut client): Graph,
+    Extension(db): Extension<Database>,
+    Extension(limits): Extension<Limits>,
+    Extension(imports): Extension<ImportDir>,
+    Path(folder): Path<String>,
+    mbox: BodyStream,
+) -> Result<Json<Job>, AppError> {
+    // Check the folder before taking in what may be gigabytes of upload.
+    client.get_folder_status_by_name(&folder).await?;
+
+    let path = imports.upload_path(user_id(&user)?);
+    let size = match store_upload(&path, mbox, limits.max_import_size).await {
+        Ok(size) => size,
+        Err(err) => {
+            if let Err(err) = tokio::fs::remove_file(&path).await {
+                error!("Failed to remove upload {}: {}", path.display(), err);
+            }
+            return Err(err);
+        }
+    };
+
+    let db_client = db.get().await?;
+    let progress = ImportProgress {
+        size,
+        ..Default::default()
+    };
+    let data = json!({
+        "user_email": user.email,
+        "folder": folder,
+        "path": path,
+        "progress": progress,
+    });
+    let job_id = enqueue(&db_client, IMPORT_MBOX_TASK, data, Utc::now(), None).await?;
+    let task = get_task(&db_client, job_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Job not found: {}", job_id)))?;
+    Ok(Json(task.into()))
+}
+
+/// Writes a request body to a file, failing once it grows over `max_size`
+/// bytes. Returns the size written.
+async fn store_upload(
+    path: &FilePath,
+    mut body: BodyStream,
+    max_size: u64,
+) -> Result<u64, AppError> {
+    let mut file = File::create(path).await.map_err(anyhow::Error::from)?;
+    let mut size = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|err| AppError::BadRequest(err.to_string()))?;
+        size += chunk.len() as u64;
+        if size > max_size {
+            return Err(AppError::PayloadTooLarge(format!(
+                "Upload is over the {} byte limit",
+                max_size
+            )));
+        }
+        file.write_all(&chunk).await.map_err(anyhow::Error::from)?;
+    }
+    file.flush().await.map_err(anyhow::Error::from)?;
+    Ok(size)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/folders/status",
+    tag = "folders",
+    responses(
+        (status = 200, description = "Message counts for every folder", body = [FolderStatus])
+    )
+)]
+async fn get_folders_status(Graph(client): Graph) -> Result<Json<Vec<FolderStatus>>, AppError> {
+    Ok(Json(client.get_folders_status().await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/folders/delta",
+    tag = "folders",
+    params(DeltaQuery),
+    responses(
+        (status = 200, description = "Changes to the folder hierarchy since the cursor", body = FolderDelta),
+        (status = 400, description = "Invalid request", body = ErrorMessage)
+    )
+)]
+async fn get_folders_delta(
+    Graph(client): Graph,
+    Query(query): Query<DeltaQuery>,
+) -> Result<Json<FolderDelta>, AppError> {
+    Ok(Json(
+        client.get_folders_delta(query.cursor.as_deref()).await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/folders/{folder}/status",
+    tag = "folders",
+    params(("folder" = String, Path, description = "Folder name, path or alias")),
+    responses(
+        (status = 200, description = "Message counts for the folder", body = FolderStatus),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn get_folder_status(
+    Graph(mut client): Graph,
+    Path(folder): Path<String>,
+) -> Result<Json<FolderStatus>, AppError> {
+    Ok(Json(client.get_folder_status_by_name(&folder).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/folders/{folder}/messages",
+    tag = "folders",
+    params(("folder" = String, Path, description = "Folder name, path or alias"), MessagesQuery),
+    responses(
+        (status = 200, description = "One page of messages", body = EmailPage),
+        (status = 400, description = "Invalid request", body = ErrorMessage),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn get_folder_messages(
+    Graph(mut client): Graph,
+    Path(folder): Path<String>,
+    Query(query): Query<MessagesQuery>,
+) -> Result<Json<EmailPage>, AppError> {
+    Ok(Json(
+        client
+            .get_folder_emails_page(&folder, &query.options()?, query.cursor.as_deref())
+            .await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/folders/{folder}/export",
+    tag = "folders",
+    params(("folder" = String, Path, description = "Folder name, path or alias")),
+    responses(
+        (status = 200, description = "The folder as an mbox file", content_type = "application/mbox"),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn get_folder_export(
+    Graph(client): Graph,
+    Path(folder): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let stream = client.export_folder_mbox(&folder).await?;
+    let file_name: String = folder
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let headers = [
+        (header::CONTENT_TYPE, "application/mbox".to_string()),
+        (
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.mbox\"", file_name),
+        ),
+    ];
+    Ok((headers, StreamBody::new(stream)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/folders/{folder}/dedupe",
+    tag = "folders",
+    params(("folder" = String, Path, description = "Folder name, path or alias"), DedupeQuery),
+    responses(
+        (status = 200, description = "The duplicates found", body = DedupeReport),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn post_folder_dedupe(
+    Graph(mut client): Graph,
+    Path(folder): Path<String>,
+    Query(query): Query<DedupeQuery>,
+) -> Result<Json<DedupeReport>, AppError> {
+    Ok(Json(client.dedupe_folder(&folder, query.dry_run).await?))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/folders/{folder}/messages",
+    tag = "folders",
+    params(("folder" = String, Path, description = "Folder name, path or alias")),
+    request_body = EmailUpdate,
+    responses(
+        (status = 200, description = "The outcome for each message", body = [BatchResult]),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn patch_folder_messages(
+    Graph(mut client): Graph,
+    Path(folder): Path<String>,
+    Json(update): Json<EmailUpdate>,
+) -> Result<Json<Vec<BatchResult>>, AppError> {
+    Ok(Json(client.update_folder_emails(&folder, &update).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/folders/{folder}/delta",
+    tag = "folders",
+    params(("folder" = String, Path, description = "Folder name, path or alias"), DeltaQuery),
+    responses(
+        (status = 200, description = "Changes since the cursor", body = EmailDelta),
+        (status = 400, description = "Invalid request", body = ErrorMessage),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn get_folder_delta(
+    Graph(mut client): Graph,
+    Path(folder): Path<String>,
+    Query(query): Query<DeltaQuery>,
+) -> Result<Json<EmailDelta>, AppError> {
+    Ok(Json(
+        client
+            .get_folder_delta(&folder, query.cursor.as_deref())
+            .await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/{folder}/emails",
+    tag = "folders",
+    params(("folder" = String, Path, description = "Folder name, path or alias"), ListQuery),
+    responses(
+        (status = 200, description = "Messages in the folder", body = [Email]),
+        (status = 400, description = "Invalid request", body = ErrorMessage),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
 async fn get_folder_emails(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
+    Graph(mut client): Graph,
     Path(folder): Path<String>,
+    Query(query): Query<ListQuery>,
 ) -> Result<Json<Vec<Email>>, AppError> {
-    let mut client = GraphClient::new(access_code.token().to_owned());
     Ok(Json(
-        client.get_user_emails_from_folder_by_name(&folder).await?,
+        client
+            .get_user_emails_from_folder_by_name(&folder, query.sort()?)
+            .await?,
     ))
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/{folder}/threads",
+    tag = "folders",
+    params(("folder" = String, Path, description = "Folder name, path or alias")),
+    responses(
+        (status = 200, description = "Messages in the folder grouped by conversation", body = [Thread]),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn get_folder_threads(
+    Graph(mut client): Graph,
+    Path(folder): Path<String>,
+) -> Result<Json<Vec<Thread>>, AppError> {
+    Ok(Json(
+        client.get_user_threads_from_folder_by_name(&folder).await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/emails/{id}",
+    tag = "emails",
+    params(("id" = String, Path, description = "Message id"), EmailQuery),
+    responses(
+        (status = 200, description = "The message, with its HTML body sanitized", body = SanitizedEmail),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
 async fn get_email(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
+    Graph(client): Graph,
+    Extension(limits): Extension<Limits>,
+    Path(id): Path<String>,
+    Query(query): Query<EmailQuery>,
+) -> Result<Json<SanitizedEmail>, AppError> {
+    let email = if query.inline_images {
+        client
+            .get_email_with_inline_images(&id, limits.max_attachment_size)
+            .await?
+    } else {
+        client.get_email_by_id(&id).await?
+    };
+    Ok(Json(SanitizedEmail::new(email, query.remote_content)))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/emails/{id}",
+    tag = "emails",
+    params(("id" = String, Path, description = "Message id")),
+    request_body = EmailUpdate,
+    responses(
+        (status = 200, description = "The updated message", body = Email),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn patch_email(
+    Graph(client): Graph,
+    Path(id): Path<String>,
+    Json(update): Json<EmailUpdate>,
+) -> Result<Json<Email>, AppError> {
+    Ok(Json(client.update_email(&id, &update).await?))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/emails/{id}",
+    tag = "emails",
+    params(("id" = String, Path, description = "Message id"), DeleteQuery),
+    responses(
+        (status = 200, description = "The message, moved to Deleted Items, with the folder it was in", body = DeletedEmail),
+        (status = 204, description = "The message was permanently deleted"),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn delete_email(
+    Graph(mut client): Graph,
+    Path(id): Path<String>,
+    Query(query): Query<DeleteQuery>,
+) -> Result<Response, AppError> {
+    if query.permanent {
+        client.delete_email_permanently(&id).await?;
+        Ok(StatusCode::NO_CONTENT.into_response())
+    } else {
+        Ok(Json(client.delete_email(&id).await?).into_response())
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/emails/{id}/forward-as-attachment",
+    tag = "emails",
+    params(("id" = String, Path, description = "Message id")),
+    request_body = OutgoingMessage,
+    responses(
+        (status = 200, description = "The draft with the message attached", body = Email),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn post_forward_as_attachment(
+    Graph(client): Graph,
     Path(id): Path<String>,
+    Json(message): Json<OutgoingMessage>,
 ) -> Result<Json<Email>, AppError> {
-    let client = GraphClient::new(access_code.token().to_owned());
-    Ok(Json(client.get_email_by_id(&id).await?))
+    Ok(Json(client.forward_as_attachment(&id, message).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/emails/{id}/conversation",
+    tag = "emails",
+    params(("id" = String, Path, description = "Message id")),
+    responses(
+        (status = 200, description = "The message's conversation", body = Conversation),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn get_email_conversation(
+    Graph(client): Graph,
+    Path(id): Path<String>,
+) -> Result<Json<Conversation>, AppError> {
+    Ok(Json(client.get_conversation(&id).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/emails/{id}/auth-results",
+    tag = "emails",
+    params(("id" = String, Path, description = "Message id")),
+    responses(
+        (status = 200, description = "SPF, DKIM and DMARC results", body = AuthResults),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn get_email_auth_results(
+    Graph(client): Graph,
+    Path(id): Path<String>,
+) -> Result<Json<AuthResults>, AppError> {
+    Ok(Json(client.get_auth_results(&id).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/emails/{id}/raw",
+    tag = "emails",
+    params(("id" = String, Path, description = "Message id")),
+    responses(
+        (status = 200, description = "The raw message", content_type = "message/rfc822"),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn get_email_raw(
+    Graph(client): Graph,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let stream = client.get_email_mime_stream(&id).await?;
+    Ok((
+        [(header::CONTENT_TYPE, "message/rfc822")],
+        StreamBody::new(stream),
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/emails/{id}/attachments",
+    tag = "emails",
+    params(("id" = String, Path, description = "Message id")),
+    responses(
+        (status = 200, description = "The message's attachments", body = [Attachment]),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn get_email_attachments(
+    Graph(client): Graph,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<Attachment>>, AppError> {
+    Ok(Json(client.get_email_attachments(&id).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/emails/{id}/attachments/{attachment_id}",
+    tag = "emails",
+    params(("id" = String, Path, description = "Message id"), ("attachment_id" = String, Path, description = "Attachment id")),
+    responses(
+        (status = 200, description = "The attachment content"),
+        (status = 404, description = "Not found", body = ErrorMessage),
+        (status = 413, description = "The attachment is too large", body = ErrorMessage)
+    )
+)]
+async fn get_attachment(
+    Graph(client): Graph,
+    Extension(limits): Extension<Limits>,
+    Path((email_id, attachment_id)): Path<(String, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    let attachment = client
+        .get_attachment_content(&email_id, &attachment_id, limits.max_attachment_size)
+        .await?;
+    let headers = [
+        (header::CONTENT_TYPE, attachment.content_type.clone()),
+        (
+            header::CONTENT_DISPOSITION,
+            content_disposition(&attachment.name),
+        ),
+    ];
+    Ok((headers, StreamBody::new(attachment.bytes_stream())))
+}
+
+/// Serves a thumbnail of an image attachment. The attachment is looked up
+/// with Graph on every request, so cached thumbnails are only served to users
+/// that can read the attachment.
+#[utoipa::path(
+    get,
+    path = "/api/emails/{id}/attachments/{attachment_id}/thumbnail",
+    tag = "emails",
+    params(
+        ("id" = String, Path, description = "Message id"),
+        ("attachment_id" = String, Path, description = "Attachment id"),
+        ThumbnailQuery
+    ),
+    responses(
+        (status = 200, description = "A PNG or JPEG thumbnail of the image"),
+        (status = 404, description = "Not found", body = ErrorMessage),
+        (status = 413, description = "The attachment is too large", body = ErrorMessage),
+        (status = 415, description = "The attachment isn't a supported image", body = ErrorMessage)
+    )
+)]
+async fn get_attachment_thumbnail(
+    Graph(client): Graph,
+    Extension(limits): Extension<Limits>,
+    Extension(thumbnails): Extension<ThumbnailCache>,
+    Path((email_id, attachment_id)): Path<(String, String)>,
+    Query(query): Query<ThumbnailQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let size = query.size.unwrap_or(DEFAULT_SIZE).clamp(1, MAX_SIZE);
+    let attachment = client.get_attachment(&email_id, &attachment_id).await?;
+    let content_type = attachment.content_type.as_deref().unwrap_or_default();
+    if !thumbnail::is_supported(content_type) {
+        return Err(AppError::UnsupportedMediaType(format!(
+            "No thumbnail for attachments of type {}",
+            content_type
+        )));
+    }
+
+    let thumbnail = match thumbnails.get(&email_id, &attachment_id, size) {
+        Some(thumbnail) => thumbnail,
+        None => {
+            let bytes = client
+                .download_attachment(&email_id, attachment, limits.max_attachment_size)
+                .await?
+                .bytes()
+                .await?;
+            let thumbnail = tokio::task::spawn_blocking(move || thumbnail::render(&bytes, size))
+                .await
+                .map_err(anyhow::Error::from)?
+                .map_err(|err| AppError::UnsupportedMediaType(format!("Invalid image: {}", err)))?;
+            thumbnails.put(&email_id, &attachment_id, size, thumbnail.clone());
+            thumbnail
+        }
+    };
+
+    let headers = [
+        (header::CONTENT_TYPE, thumbnail.content_type),
+        (header::CACHE_CONTROL, "private, max-age=86400"),
+    ];
+    Ok((headers, thumbnail.bytes))
 }
 
+/// Builds an `attachment` Content-Disposition header for a file name. Control
+/// characters are dropped. The name is sent as an ASCII `filename` fallback
+/// and as an RFC 6266 `filename*` parameter that keeps non-ASCII characters.
+fn content_disposition(file_name: &str) -> String {
+    let file_name: String = file_name.chars().filter(|c| !c.is_control()).collect();
+    let fallback: String = file_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let encoded: String = file_name
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/emails/move/{folder}",
+    tag = "emails",
+    params(("folder" = String, Path, description = "Folder name, path or alias")),
+    request_body(content = [String], description = "Ids of the messages to move"),
+    responses(
+        (status = 200, description = "The outcome for each message", body = [BatchResult]),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
 async fn put_bulk_move(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
+    Graph(mut client): Graph,
     Path(folder): Path<String>,
     Json(email_ids): Json<Vec<String>>,
-) -> Result<Json<Vec<Email>>, AppError> {
+) -> Result<Json<Vec<BatchResult>>, AppError> {
     info!("Moving {email_ids:?} to {folder}...");
-    let mut client = GraphClient::new(access_code.token().to_owned());
     Ok(Json(
         client
             .move_emails_to_folder_by_name(email_ids, &folder)
@@ -175,12 +1947,21 @@ async fn put_bulk_move(
     ))
 }
 
+#[utoipa::path(
+    put,
+    path = "/api/emails/{id}/move/{folder}",
+    tag = "emails",
+    params(("id" = String, Path, description = "Message id"), ("folder" = String, Path, description = "Folder name, path or alias")),
+    responses(
+        (status = 200, description = "The moved message", body = Email),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
 async fn put_move(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
+    Graph(mut client): Graph,
     Path((email_id, folder_name)): Path<(String, String)>,
 ) -> Result<Json<Email>, AppError> {
     info!("Moving {email_id} to {folder_name}...");
-    let mut client = GraphClient::new(access_code.token().to_owned());
     Ok(Json(
         client
             .move_email_to_folder_by_name(&email_id, &folder_name)
@@ -188,26 +1969,178 @@ async fn put_move(
     ))
 }
 
+#[utoipa::path(
+    put,
+    path = "/api/emails/{id}/archive",
+    tag = "emails",
+    params(("id" = String, Path, description = "Message id")),
+    responses(
+        (status = 200, description = "The archived message", body = Email),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
 async fn put_archive(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
+    Graph(mut client): Graph,
     Path(email_id): Path<String>,
 ) -> Result<Json<Email>, AppError> {
-    let mut client = GraphClient::new(access_code.token().to_owned());
+    let folder_id = client.get_standard_folder_id("archive").await?;
     Ok(Json(
-        client
-            .move_email_to_folder_by_name(&email_id, "Archive")
-            .await?,
+        client.move_email_to_folder(&email_id, &folder_id).await?,
     ))
 }
 
+/// Moves an email out of the way until `until`, when a queued task moves it
+/// back to the inbox as unread. Returns the job that will wake it up.
+#[utoipa::path(
+    put,
+    path = "/api/emails/{id}/snooze",
+    tag = "emails",
+    params(("id" = String, Path, description = "Message id")),
+    request_body = SnoozeRequest,
+    responses(
+        (status = 200, description = "The job that will unsnooze the message", body = Job),
+        (status = 400, description = "Invalid request", body = ErrorMessage),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn put_snooze(
+    AuthUser(user): AuthUser,
+    Graph(client): Graph,
+    Extension(db): Extension<Database>,
+    Path(id): Path<String>,
+    Json(request): Json<SnoozeRequest>,
+) -> Result<Json<Job>, AppError> {
+    if request.until <= Utc::now() {
+        return Err(AppError::BadRequest(
+            "snooze time must be in the future".to_string(),
+        ));
+    }
+
+    let folder = client.get_or_create_hidden_folder(SNOOZED_FOLDER).await?;
+    let email = client.move_email_to_folder(&id, &folder.id).await?;
+
+    let db_client = db.get().await?;
+    let data = json!({ "user_email": user.email, "email_id": email.id });
+    let job_id = enqueue(&db_client, UNSNOOZE_TASK, data, request.until, None).await?;
+    let task = get_task(&db_client, job_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("Job not found: {}", job_id)))?;
+    Ok(Json(task.into()))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/emails/{id}/spam",
+    tag = "emails",
+    params(("id" = String, Path, description = "Message id")),
+    responses(
+        (status = 200, description = "The message, moved to Junk", body = Email),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
 async fn put_mark_spam(
-    TypedHeader(access_code): TypedHeader<Authorization<Bearer>>,
+    Graph(mut client): Graph,
+    Extension(spam): Extension<SpamLearning>,
     Path(email_id): Path<String>,
 ) -> Result<Json<Email>, AppError> {
-    let mut client = GraphClient::new(access_code.token().to_owned());
-    Ok(Json(
-        client
-            .move_email_to_folder_by_name(&email_id, "Junk Email")
-            .await?,
-    ))
+    Ok(Json(report(&mut client, &spam, &email_id, true).await?))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/emails/{id}/ham",
+    tag = "emails",
+    params(("id" = String, Path, description = "Message id")),
+    responses(
+        (status = 200, description = "The message, moved to the Inbox", body = Email),
+        (status = 404, description = "Not found", body = ErrorMessage)
+    )
+)]
+async fn put_mark_ham(
+    Graph(mut client): Graph,
+    Extension(spam): Extension<SpamLearning>,
+    Path(email_id): Path<String>,
+) -> Result<Json<Email>, AppError> {
+    Ok(Json(report(&mut client, &spam, &email_id, false).await?))
+}
+
+/// Moves a message to Junk (spam) or back to the Inbox (ham), then feeds it
+/// to the configured learning command. A failing command is logged but does
+/// not fail the request, since the message has already been moved.
+async fn report(
+    client: &mut GraphClient,
+    spam: &SpamLearning,
+    email_id: &str,
+    is_spam: bool,
+) -> Result<Email, AppError> {
+    let mime = match spam.command(is_spam) {
+        Some(_) => Some(client.get_email_mime(email_id).await?),
+        None => None,
+    };
+
+    let folder = if is_spam { "junkemail" } else { "inbox" };
+    let folder_id = client.get_standard_folder_id(folder).await?;
+    let email = client.move_email_to_folder(email_id, &folder_id).await?;
+
+    if let (Some(command), Some(mime)) = (spam.command(is_spam), mime) {
+        if let Err(err) = pipe_to_command(command, &mime).await {
+            error!("Spam learning failed for {email_id}: {err:?}");
+        }
+    }
+
+    Ok(email)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("report.pdf"),
+            "attachment; filename=\"report.pdf\"; filename*=UTF-8''report.pdf"
+        );
+        assert_eq!(
+            content_disposition("relatório \"final\".pdf"),
+            "attachment; filename=\"relat_rio _final_.pdf\"; \
+             filename*=UTF-8''relat%C3%B3rio%20%22final%22.pdf"
+        );
+
+        let header = content_disposition("evil\r\nSet-Cookie: x.txt");
+        assert!(HeaderValue::from_str(&header).is_ok());
+        assert!(header.starts_with("attachment; filename=\"evilSet-Cookie: x.txt\""));
+    }
+
+    #[test]
+    fn test_limit_body() {
+        futures::executor::block_on(async {
+            let body = |size: usize| async move {
+                BodyStream::from_request(Request::new(Body::from(vec![b'x'; size])), &())
+                    .await
+                    .unwrap()
+            };
+
+            let Ok((stream, exceeded)) = limit_body(&HeaderMap::new(), body(10).await, 10) else {
+                panic!("body rejected up front");
+            };
+            let chunks: Vec<_> = stream.collect().await;
+            assert!(chunks.iter().all(Result::is_ok));
+            assert!(!exceeded.load(Ordering::Relaxed));
+
+            let Ok((stream, exceeded)) = limit_body(&HeaderMap::new(), body(11).await, 10) else {
+                panic!("body rejected up front");
+            };
+            let chunks: Vec<_> = stream.collect().await;
+            assert!(chunks.iter().any(Result::is_err));
+            assert!(exceeded.load(Ordering::Relaxed));
+
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("11"));
+            let err = limit_body(&headers, body(11).await, 10).err().unwrap();
+            assert_eq!(err.into_response().status(), StatusCode::PAYLOAD_TOO_LARGE);
+        });
+    }
 }
@@ -0,0 +1,237 @@
+use axum::{response::Html, Json};
+use serde::Serialize;
+use utoipa::{
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+    Modify, OpenApi, ToSchema,
+};
+
+use crate::{
+    database::{FolderAlias, SavedSearch, User},
+    graph::{
+        Attachment, AuthResults, AuthVerdict, AutomaticReplies, BatchResult, Body, Conversation,
+        ConversationMessage, DateTimeTimeZone, DedupeReport, DeletedEmail, Duplicate, Email,
+        EmailAddress, EmailAddressWrapper, EmailDelta, EmailPage, EmailUpdate, Flag, FlagStatus,
+        Folder, FolderDelta, FolderStatus, OutgoingAttachment, OutgoingMessage, Profile,
+        Subscription, Tag, Thread, ThreadNode,
+    },
+    import::ImportProgress,
+};
+
+use super::{
+    Check, FolderAliasRequest, FolderRequest, Health, Job, RenewSubscriptionRequest,
+    SanitizedEmail, SavedSearchRequest, SnoozeRequest, SubscriptionRequest, TokenRequest,
+};
+
+/// Body of every error response.
+#[derive(Serialize, ToSchema)]
+pub struct ErrorMessage {
+    message: String,
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        super::get_profile,
+        super::get_health,
+        super::post_token,
+        super::get_search,
+        super::get_saved_searches,
+        super::put_saved_search,
+        super::delete_saved_search,
+        super::get_saved_search_messages,
+        super::get_folder_aliases,
+        super::put_folder_alias,
+        super::delete_folder_alias,
+        super::post_index_job,
+        super::get_job,
+        super::post_resume_job,
+        super::get_emails,
+        super::put_bulk_move,
+        super::get_email,
+        super::patch_email,
+        super::delete_email,
+        super::get_email_raw,
+        super::get_email_conversation,
+        super::get_email_auth_results,
+        super::get_email_attachments,
+        super::get_attachment,
+        super::get_attachment_thumbnail,
+        super::put_move,
+        super::put_email_tag,
+        super::delete_email_tag,
+        super::put_archive,
+        super::post_forward_as_attachment,
+        super::put_snooze,
+        super::put_mark_spam,
+        super::put_mark_ham,
+        super::get_subscriptions,
+        super::post_subscription,
+        super::patch_subscription,
+        super::delete_subscription,
+        super::get_tags,
+        super::get_vacation,
+        super::put_vacation,
+        super::post_send_message,
+        super::get_drafts,
+        super::post_draft,
+        super::patch_draft,
+        super::delete_draft,
+        super::post_send_draft,
+        super::get_folders,
+        super::post_folder,
+        super::patch_folder,
+        super::delete_folder,
+        super::get_folders_status,
+        super::get_folders_delta,
+        super::get_folder_status,
+        super::get_folder_messages,
+        super::patch_folder_messages,
+        super::post_folder_import,
+        super::get_folder_delta,
+        super::post_folder_dedupe,
+        super::get_folder_export,
+        super::post_folder_import_mbox,
+        super::get_folder_emails,
+        super::get_folder_threads,
+    ),
+    components(schemas(
+        Attachment,
+        AuthResults,
+        AuthVerdict,
+        AutomaticReplies,
+        BatchResult,
+        Body,
+        Check,
+        Conversation,
+        ConversationMessage,
+        DateTimeTimeZone,
+        DedupeReport,
+        DeletedEmail,
+        Duplicate,
+        Email,
+        EmailAddress,
+        EmailAddressWrapper,
+        EmailDelta,
+        EmailPage,
+        EmailUpdate,
+        ErrorMessage,
+        Flag,
+        FlagStatus,
+        Folder,
+        FolderAlias,
+        FolderAliasRequest,
+        FolderDelta,
+        FolderRequest,
+        FolderStatus,
+        Health,
+        ImportProgress,
+        Job,
+        OutgoingAttachment,
+        OutgoingMessage,
+        Profile,
+        RenewSubscriptionRequest,
+        SanitizedEmail,
+        SavedSearch,
+        SavedSearchRequest,
+        SnoozeRequest,
+        Subscription,
+        SubscriptionRequest,
+        Tag,
+        Thread,
+        ThreadNode,
+        TokenRequest,
+        User,
+    )),
+    modifiers(&BearerAuth),
+    security(("bearer" = [])),
+    tags(
+        (name = "auth", description = "Registering access tokens"),
+        (name = "drafts", description = "Composing messages"),
+        (name = "emails", description = "Reading and organizing messages"),
+        (name = "folders", description = "Mail folders and their aliases"),
+        (name = "health", description = "Server health"),
+        (name = "jobs", description = "Background jobs"),
+        (name = "messages", description = "Sending messages"),
+        (name = "profile", description = "The signed-in user"),
+        (name = "search", description = "Full-text search of indexed messages"),
+        (name = "searches", description = "Saved searches"),
+        (name = "subscriptions", description = "Change notification webhooks"),
+        (name = "tags", description = "Message categories"),
+        (name = "vacation", description = "Automatic replies"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Declares the Microsoft Graph access token that every request carries as
+/// a bearer token.
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "bearer",
+                SecurityScheme::Http(
+                    HttpBuilder::new()
+                        .scheme(HttpAuthScheme::Bearer)
+                        .bearer_format("JWT")
+                        .description(Some("A Microsoft Graph access token"))
+                        .build(),
+                ),
+            );
+        }
+    }
+}
+
+/// Swagger UI page for the API description. The UI itself is loaded from a
+/// CDN so the server doesn't have to bundle it.
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html lang="en">
+  <head>
+    <meta charset="utf-8" />
+    <title>postrs API</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+  </head>
+  <body>
+    <div id="swagger-ui"></div>
+    <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+    <script>
+      window.ui = SwaggerUIBundle({ url: "/api/openapi.json", dom_id: "#swagger-ui" });
+    </script>
+  </body>
+</html>
+"##;
+
+pub async fn get_openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+pub async fn get_docs() -> Html<&'static str> {
+    Html(SWAGGER_UI)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_openapi() {
+        let doc = ApiDoc::openapi();
+        let emails = &doc.paths.paths["/api/emails/{id}"];
+        assert_eq!(emails.operations.len(), 3);
+        assert!(doc
+            .paths
+            .paths
+            .contains_key("/api/folders/{folder}/messages"));
+
+        let schemas = &doc.components.as_ref().unwrap().schemas;
+        assert!(schemas.contains_key("Email"));
+        assert!(schemas.contains_key("OutgoingMessage"));
+
+        let json = serde_json::to_value(&doc).unwrap();
+        assert_eq!(
+            json["components"]["securitySchemes"]["bearer"]["scheme"],
+            "bearer"
+        );
+    }
+}
@@ -14,6 +14,17 @@ use thiserror::Error;
 use tracing::trace;
 use url::Url;
 
+use crate::{
+    database::{DatabaseError, User},
+    graph::{GraphClientError, HttpClient},
+};
+
+/// Microsoft identity platform endpoint that issues access tokens.
+pub const TOKEN_URL: &str = "https://login.microsoftonline.com/common/oauth2/v2.0/token";
+
+/// Permissions requested for the signed-in user.
+pub const SCOPES: &str = "openid profile email offline_access https://graph.microsoft.com/Mail.Read https://graph.microsoft.com/Mail.ReadWrite https://graph.microsoft.com/Mail.Send https://graph.microsoft.com/MailboxSettings.ReadWrite";
+
 #[derive(Debug, Error)]
 pub enum AuthError {
     #[error("Invalid URL: {0}")]
@@ -24,6 +35,15 @@ pub enum AuthError {
 
     #[error("No token present")]
     NoTokenPresent,
+
+    #[error("No refresh token for {0}")]
+    NoRefreshToken(String),
+
+    #[error("Token refresh failed: {0}")]
+    Refresh(#[from] GraphClientError),
+
+    #[error("Database error: {0}")]
+    Database(#[from] DatabaseError),
 }
 
 #[derive(Default, Serialize, Deserialize, Debug)]
@@ -43,8 +63,7 @@ pub fn auth() -> Result<Token, AuthError> {
     let client_secret = ClientSecret::new(env::var("CLIENT_SECRET")?);
     let auth_url =
         AuthUrl::new("https://login.microsoftonline.com/common/oauth2/v2.0/authorize".to_string())?;
-    let token_url =
-        TokenUrl::new("https://login.microsoftonline.com/common/oauth2/v2.0/token".to_string())?;
+    let token_url = TokenUrl::new(TOKEN_URL.to_string())?;
 
     // Set up the config for the Microsoft Graph OAuth2 process.
     let client = BasicClient::new(client_id, Some(client_secret), auth_url, Some(token_url))
@@ -58,9 +77,7 @@ pub fn auth() -> Result<Token, AuthError> {
     // Generate the authorization URL to which we'll redirect the user.
     let (authorize_url, csrf_state) = client
         .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new(
-            "openid profile email offline_access https://graph.microsoft.com/Mail.Read https://graph.microsoft.com/Mail.ReadWrite".to_string(),
-        ))
+        .add_scope(Scope::new(SCOPES.to_string()))
         .set_pkce_challenge(pkce_code_challenge)
         .url();
 
@@ -162,3 +179,27 @@ pub fn auth() -> Result<Token, AuthError> {
 
     Err(AuthError::NoTokenPresent)
 }
+
+/// Exchanges a user's stored refresh token for a new access token and saves
+/// the new tokens. Background jobs run long after the user registered their
+/// tokens, by which time the stored access token has usually expired.
+pub async fn refresh_user_token(
+    http: &HttpClient,
+    client: &deadpool_postgres::Client,
+    user: &User,
+) -> Result<String, AuthError> {
+    let refresh_token = user
+        .refresh_token
+        .as_deref()
+        .ok_or_else(|| AuthError::NoRefreshToken(user.email.clone()))?;
+    let client_id = env::var("CLIENT_ID")?;
+    let client_secret = env::var("CLIENT_SECRET").ok().filter(|s| !s.is_empty());
+
+    let grant = http
+        .refresh_token(&client_id, client_secret.as_deref(), refresh_token)
+        .await?;
+    let refresh_token = grant.refresh_token.as_deref().unwrap_or(refresh_token);
+    user.update_tokens(client, &grant.access_token, refresh_token)
+        .await?;
+    Ok(grant.access_token)
+}
@@ -3,6 +3,7 @@ use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio_postgres::NoTls;
 use url::Url;
+use utoipa::ToSchema;
 
 pub type Result<T> = std::result::Result<T, DatabaseError>;
 
@@ -61,9 +62,13 @@ impl Database {
     pub async fn get(&self) -> Result<deadpool_postgres::Client> {
         Ok(self.pool.get().await?)
     }
+
+    pub fn pool(&self) -> &Pool {
+        &self.pool
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct User {
     pub id: Option<i32>,
     pub email: String,
@@ -109,7 +114,27 @@ impl User {
         })
     }
 
-    #[allow(unused)]
+    /// Returns the layout version of the documents in the user's search index.
+    pub async fn index_version(&self, client: &deadpool_postgres::Client) -> Result<i32> {
+        let stmt = client
+            .prepare("SELECT index_version FROM users WHERE email = $1")
+            .await?;
+        let row = client.query_one(&stmt, &[&self.email]).await?;
+        Ok(row.get(0))
+    }
+
+    pub async fn set_index_version(
+        &self,
+        client: &deadpool_postgres::Client,
+        version: i32,
+    ) -> Result<()> {
+        let stmt = client
+            .prepare("UPDATE users SET index_version = $1 WHERE email = $2")
+            .await?;
+        client.execute(&stmt, &[&version, &self.email]).await?;
+        Ok(())
+    }
+
     pub async fn update_tokens(
         &self,
         client: &deadpool_postgres::Client,
@@ -126,6 +151,139 @@ impl User {
     }
 }
 
+/// A named query over a folder that clients can list like a folder.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct SavedSearch {
+    pub name: String,
+    pub folder: String,
+    /// A search query in Graph's KQL syntax.
+    pub query: String,
+}
+
+impl SavedSearch {
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        Self {
+            name: row.get(0),
+            folder: row.get(1),
+            query: row.get(2),
+        }
+    }
+
+    pub async fn list(client: &deadpool_postgres::Client, user_id: i32) -> Result<Vec<Self>> {
+        let stmt = client
+            .prepare(
+                "SELECT name, folder, query FROM saved_searches WHERE user_id = $1 ORDER BY name",
+            )
+            .await?;
+        let rows = client.query(&stmt, &[&user_id]).await?;
+        Ok(rows.iter().map(Self::from_row).collect())
+    }
+
+    pub async fn find(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        name: &str,
+    ) -> Result<Option<Self>> {
+        let stmt = client
+            .prepare(
+                "SELECT name, folder, query FROM saved_searches WHERE user_id = $1 AND name = $2",
+            )
+            .await?;
+        let rows = client.query(&stmt, &[&user_id, &name]).await?;
+        Ok(rows.first().map(Self::from_row))
+    }
+
+    pub async fn upsert(&self, client: &deadpool_postgres::Client, user_id: i32) -> Result<()> {
+        let stmt = client
+            .prepare(
+                "INSERT INTO saved_searches (user_id, name, folder, query) VALUES ($1, $2, $3, $4)
+                ON CONFLICT (user_id, name) DO UPDATE SET folder = $3, query = $4",
+            )
+            .await?;
+        client
+            .execute(&stmt, &[&user_id, &self.name, &self.folder, &self.query])
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes a saved search, returning whether it existed.
+    pub async fn delete(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        name: &str,
+    ) -> Result<bool> {
+        let stmt = client
+            .prepare("DELETE FROM saved_searches WHERE user_id = $1 AND name = $2")
+            .await?;
+        Ok(client.execute(&stmt, &[&user_id, &name]).await? > 0)
+    }
+}
+
+/// Maps a logical folder name such as `sent` to the folder path that holds it
+/// in one user's mailbox, e.g. `[Gmail]/Sent Mail`.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct FolderAlias {
+    pub alias: String,
+    pub folder: String,
+}
+
+impl FolderAlias {
+    fn from_row(row: &tokio_postgres::Row) -> Self {
+        Self {
+            alias: row.get(0),
+            folder: row.get(1),
+        }
+    }
+
+    pub async fn list(client: &deadpool_postgres::Client, user_id: i32) -> Result<Vec<Self>> {
+        let stmt = client
+            .prepare("SELECT alias, folder FROM folder_aliases WHERE user_id = $1 ORDER BY alias")
+            .await?;
+        let rows = client.query(&stmt, &[&user_id]).await?;
+        Ok(rows.iter().map(Self::from_row).collect())
+    }
+
+    /// Lists the aliases of the user with the given email, if any.
+    pub async fn list_by_email(
+        client: &deadpool_postgres::Client,
+        email: &str,
+    ) -> Result<Vec<Self>> {
+        let stmt = client
+            .prepare(
+                "SELECT a.alias, a.folder FROM folder_aliases a
+                JOIN users u ON u.id = a.user_id WHERE u.email = $1",
+            )
+            .await?;
+        let rows = client.query(&stmt, &[&email]).await?;
+        Ok(rows.iter().map(Self::from_row).collect())
+    }
+
+    pub async fn upsert(&self, client: &deadpool_postgres::Client, user_id: i32) -> Result<()> {
+        let stmt = client
+            .prepare(
+                "INSERT INTO folder_aliases (user_id, alias, folder) VALUES ($1, $2, $3)
+                ON CONFLICT (user_id, alias) DO UPDATE SET folder = $3",
+            )
+            .await?;
+        client
+            .execute(&stmt, &[&user_id, &self.alias, &self.folder])
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes a folder alias, returning whether it existed.
+    pub async fn delete(
+        client: &deadpool_postgres::Client,
+        user_id: i32,
+        alias: &str,
+    ) -> Result<bool> {
+        let stmt = client
+            .prepare("DELETE FROM folder_aliases WHERE user_id = $1 AND alias = $2")
+            .await?;
+        Ok(client.execute(&stmt, &[&user_id, &alias]).await? > 0)
+    }
+}
+
 /// Creates a Deadpool configuration from a database URL.
 fn create_deadpool_config_from_url(url: &str) -> std::result::Result<Config, url::ParseError> {
     let parsed_url = Url::parse(url)?;
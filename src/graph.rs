@@ -1,12 +1,43 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use reqwest::Client;
+use base64::{decode_config, encode_config, URL_SAFE_NO_PAD};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{
+    stream::{self, BoxStream},
+    Stream, StreamExt,
+};
+use metrics::{counter, histogram};
+use reqwest::{Client, Method, Proxy, RequestBuilder, Response};
 use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
 use serde_json::{json, Value};
 use thiserror::Error;
+use tracing::{debug, error};
+use url::form_urlencoded;
+use utoipa::ToSchema;
+
+use crate::auth::{SCOPES, TOKEN_URL};
 
 const GRAPH_API_BASE_URL: &str = "https://graph.microsoft.com/v1.0";
 
+/// Asks Graph for immutable item IDs, which stay the same when a message is
+/// moved to another folder, so IDs cached by API clients remain valid.
+const PREFER_IMMUTABLE_ID: &str = "IdType=\"ImmutableId\"";
+
+/// Largest total attachment size, in bytes, sent inline with a message. Graph
+/// rejects requests over 4 MB, so larger attachments are added to a draft one
+/// at a time, through upload sessions when needed.
+const INLINE_ATTACHMENTS_LIMIT: u64 = 3 * 1024 * 1024;
+
+/// Largest attachment, in bytes, Graph accepts through an upload session.
+const UPLOAD_SESSION_LIMIT: u64 = 150 * 1024 * 1024;
+
+/// Size of each upload session request. Graph requires a multiple of 320 KiB.
+const UPLOAD_CHUNK_SIZE: usize = 10 * 320 * 1024;
+
 #[derive(Error, Debug)]
 pub enum GraphClientError {
     #[error("HTTP Request Error: {0}")]
@@ -23,9 +54,21 @@ pub enum GraphClientError {
 
     #[error("Folder not found: {0}")]
     FolderNotFound(String),
+
+    #[error("Invalid folder name: {0}")]
+    InvalidFolderName(String),
+
+    #[error("Invalid cursor")]
+    InvalidCursor,
+
+    #[error("Attachment too large: {0} bytes")]
+    AttachmentTooLarge(u64),
+
+    #[error("Invalid attachment: {0}")]
+    InvalidAttachment(String),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Profile {
     pub business_phones: Vec<String>,
@@ -41,20 +84,43 @@ pub struct Profile {
     pub user_principal_name: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Folder {
     pub child_folder_count: u32,
     pub display_name: String,
     pub id: String,
+    // Not returned by folder delta queries.
+    #[serde(default)]
     pub is_hidden: bool,
     pub parent_folder_id: String,
+    #[serde(default)]
     pub size_in_bytes: u64,
     pub total_item_count: u32,
     pub unread_item_count: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderStatus {
+    pub id: String,
+    pub display_name: String,
+    pub total_item_count: u32,
+    pub unread_item_count: u32,
+}
+
+impl From<Folder> for FolderStatus {
+    fn from(folder: Folder) -> Self {
+        Self {
+            id: folder.id,
+            display_name: folder.display_name,
+            total_item_count: folder.total_item_count,
+            unread_item_count: folder.unread_item_count,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Email {
     pub id: String,
@@ -85,6 +151,8 @@ pub struct Email {
     pub bcc_recipients: Vec<EmailAddressWrapper>,
     pub reply_to: Vec<EmailAddressWrapper>,
     pub flag: Flag,
+    #[serde(default)]
+    pub categories: Vec<String>,
 }
 
 fn deserialize_null_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
@@ -96,144 +164,2406 @@ where
     Ok(opt.unwrap_or_default())
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Body {
     pub content_type: String,
     pub content: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct EmailAddressWrapper {
     pub email_address: EmailAddress,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct EmailAddress {
     pub name: String,
     pub address: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Flag {
-    pub flag_status: String,
+    pub flag_status: FlagStatus,
 }
 
-pub struct GraphClient {
-    client: Client,
-    access_token: String,
-    folder_cache: HashMap<String, String>,
+/// A tag from the mailbox's master category list. Emails reference tags by
+/// display name in their `categories`.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Tag {
+    pub id: String,
+    pub display_name: String,
+    pub color: String,
 }
 
-impl GraphClient {
-    pub fn new(access_token: String) -> Self {
-        let client = Client::new();
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum FlagStatus {
+    NotFlagged,
+    Flagged,
+    Complete,
+}
+
+/// Changes to the read and follow-up flag state of an email. Fields left as
+/// `None` are not changed.
+#[derive(Deserialize, Debug, Default, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailUpdate {
+    pub is_read: Option<bool>,
+    pub flag_status: Option<FlagStatus>,
+}
+
+impl EmailUpdate {
+    fn to_graph_json(&self) -> Value {
+        let mut update = serde_json::Map::new();
+        if let Some(is_read) = self.is_read {
+            update.insert("isRead".to_string(), json!(is_read));
+        }
+        if let Some(flag_status) = self.flag_status {
+            update.insert("flag".to_string(), json!({ "flagStatus": flag_status }));
+        }
+        Value::Object(update)
+    }
+
+    /// Builds a `$filter` matching the messages this update would change, or
+    /// `None` when the update is empty.
+    fn pending_filter(&self) -> Option<String> {
+        let mut filters = Vec::new();
+        if let Some(is_read) = self.is_read {
+            filters.push(format!("isRead eq {}", !is_read));
+        }
+        if let Some(flag_status) = self.flag_status {
+            filters.push(format!(
+                "flag/flagStatus ne '{}'",
+                json!(flag_status).as_str()?
+            ));
+        }
+        if filters.is_empty() {
+            None
+        } else {
+            Some(filters.join(" or "))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Attachment {
+    pub id: String,
+    pub name: String,
+    pub content_type: Option<String>,
+    pub size: u64,
+    pub is_inline: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InlineAttachment {
+    pub content_type: Option<String>,
+    pub content_id: Option<String>,
+    pub content_bytes: Option<String>,
+    pub is_inline: bool,
+}
+
+#[derive(Debug)]
+pub struct AttachmentContent {
+    pub name: String,
+    pub content_type: String,
+    response: reqwest::Response,
+}
+
+impl AttachmentContent {
+    /// Streams the attachment body without buffering it in memory.
+    pub fn bytes_stream(self) -> impl Stream<Item = reqwest::Result<Bytes>> {
+        self.response.bytes_stream()
+    }
+
+    /// Reads the whole attachment body into memory.
+    pub async fn bytes(self) -> Result<Bytes, GraphClientError> {
+        Ok(self.response.bytes().await?)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DateTimeTimeZone {
+    pub date_time: String,
+    pub time_zone: String,
+}
+
+/// The mailbox's automatic replies (out of office) settings. Exchange sends
+/// the replies itself and only answers each sender once while enabled.
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AutomaticReplies {
+    /// One of `disabled`, `alwaysEnabled` or `scheduled`.
+    pub status: String,
+    /// Which external senders get a reply: `none`, `contactsOnly` or `all`.
+    pub external_audience: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scheduled_start_date_time: Option<DateTimeTimeZone>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scheduled_end_date_time: Option<DateTimeTimeZone>,
+    pub internal_reply_message: String,
+    pub external_reply_message: String,
+}
+
+/// The editable parts of a message being composed. Fields left as `None` are
+/// not sent to Graph, so updating a draft only changes the given fields.
+#[derive(Deserialize, Debug, Default, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OutgoingMessage {
+    pub subject: Option<String>,
+    pub to: Option<Vec<String>>,
+    pub cc: Option<Vec<String>>,
+    pub bcc: Option<Vec<String>>,
+    pub text: Option<String>,
+    pub html: Option<String>,
+    pub attachments: Option<Vec<OutgoingAttachment>>,
+    /// Asks the recipients' mail clients for a read receipt.
+    pub read_receipt: Option<bool>,
+}
+
+/// A file attached to an outgoing message. Inline attachments are referenced
+/// from the HTML body through `cid:<content_id>` URLs.
+#[derive(Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OutgoingAttachment {
+    pub name: String,
+    pub content_type: Option<String>,
+    /// The attachment contents, base64 encoded.
+    pub content_bytes: String,
+    pub content_id: Option<String>,
+}
+
+impl OutgoingAttachment {
+    pub fn new(name: String, content_type: Option<String>, bytes: &[u8]) -> Self {
         Self {
-            client,
-            access_token,
-            folder_cache: HashMap::new(),
+            name,
+            content_type,
+            content_bytes: base64::encode(bytes),
+            content_id: None,
         }
     }
 
-    pub async fn get_user_folders(&self) -> Result<Vec<Folder>, GraphClientError> {
-        let url = format!("{}/me/mailFolders", GRAPH_API_BASE_URL);
-        self.fetch_all_items::<Folder>(&url).await
+    /// The decoded size of the attachment, in bytes.
+    fn size(&self) -> u64 {
+        let encoded = self.content_bytes.trim_end_matches('=').len() as u64;
+        encoded * 3 / 4
     }
 
-    pub async fn get_user_emails(&self) -> Result<Vec<Email>, GraphClientError> {
-        let url = format!("{}/me/messages", GRAPH_API_BASE_URL);
-        self.fetch_all_items::<Email>(&url).await
+    fn to_graph_json(&self) -> Value {
+        json!({
+            "@odata.type": "#microsoft.graph.fileAttachment",
+            "name": self.name,
+            "contentType": self
+                .content_type
+                .as_deref()
+                .unwrap_or("application/octet-stream"),
+            "contentBytes": self.content_bytes,
+            "isInline": self.content_id.is_some(),
+            "contentId": self.content_id,
+        })
     }
+}
 
-    pub async fn get_user_emails_paginated(
-        &self,
-        initial_page: usize,
-        num_pages: usize,
-    ) -> Result<(Vec<Email>, bool), GraphClientError> {
-        let url = format!("{}/me/messages", GRAPH_API_BASE_URL);
-        self.fetch_pages::<Email>(&url, initial_page, num_pages)
-            .await
+impl OutgoingMessage {
+    /// Whether the attachments are too large to send along with the message
+    /// in a single request.
+    fn has_large_attachments(&self) -> bool {
+        let size = self
+            .attachments
+            .iter()
+            .flatten()
+            .map(OutgoingAttachment::size)
+            .sum::<u64>();
+        size > INLINE_ATTACHMENTS_LIMIT
     }
 
-    pub async fn get_user_emails_from_folder(
-        &self,
-        folder_id: &str,
-    ) -> Result<Vec<Email>, GraphClientError> {
-        let url = format!(
-            "{}/me/mailFolders/{}/messages",
-            GRAPH_API_BASE_URL, folder_id
-        );
-        let response = self
-            .client
-            .get(&url)
-            .bearer_auth(&self.access_token)
-            .send()
-            .await?;
+    /// Builds the Graph message resource for this message, leaving out the
+    /// attachments.
+    fn to_graph_json_without_attachments(&self) -> Value {
+        let mut message = self.to_graph_json();
+        if let Some(message) = message.as_object_mut() {
+            message.remove("attachments");
+        }
+        message
+    }
 
-        if response.status().is_success() {
-            let json: Value = response.json().await?;
-            let emails_value = json["value"]
-                .as_array()
-                .ok_or_else(|| GraphClientError::Parse("emails", json.clone()))?;
+    /// Builds the Graph message resource for this message. The HTML body is
+    /// used when present, otherwise the plain text one.
+    pub fn to_graph_json(&self) -> Value {
+        let mut message = serde_json::Map::new();
 
-            let emails: Result<Vec<Email>, serde_json::Error> = emails_value
+        if let Some(subject) = &self.subject {
+            message.insert("subject".to_string(), json!(subject));
+        }
+
+        for (field, addresses) in [
+            ("toRecipients", &self.to),
+            ("ccRecipients", &self.cc),
+            ("bccRecipients", &self.bcc),
+        ] {
+            if let Some(addresses) = addresses {
+                let recipients = addresses
+                    .iter()
+                    .map(|address| json!({ "emailAddress": { "address": address } }))
+                    .collect::<Vec<Value>>();
+                message.insert(field.to_string(), Value::Array(recipients));
+            }
+        }
+
+        match (&self.html, &self.text) {
+            (Some(html), _) => {
+                message.insert(
+                    "body".to_string(),
+                    json!({ "contentType": "html", "content": html }),
+                );
+            }
+            (None, Some(text)) => {
+                message.insert(
+                    "body".to_string(),
+                    json!({ "contentType": "text", "content": text }),
+                );
+            }
+            (None, None) => {}
+        }
+
+        if let Some(read_receipt) = self.read_receipt {
+            message.insert("isReadReceiptRequested".to_string(), json!(read_receipt));
+        }
+
+        if let Some(attachments) = &self.attachments {
+            let attachments = attachments
                 .iter()
-                .map(|email_value| serde_json::from_value(email_value.clone()))
-                .collect();
+                .map(OutgoingAttachment::to_graph_json)
+                .collect::<Vec<Value>>();
+            message.insert("attachments".to_string(), Value::Array(attachments));
+        }
+
+        Value::Object(message)
+    }
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Thread {
+    pub conversation_id: String,
+    pub messages: Vec<ThreadNode>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreadNode {
+    pub email: Email,
+    pub replies: Vec<ThreadNode>,
+}
+
+/// Length in bytes of the header block of a decoded `conversationIndex`.
+const CONVERSATION_INDEX_HEADER_LEN: usize = 22;
+
+/// Length in bytes of each response block appended to a `conversationIndex`.
+const CONVERSATION_INDEX_BLOCK_LEN: usize = 5;
+
+/// A conversation laid out for reading, oldest message first.
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Conversation {
+    pub conversation_id: String,
+    pub messages: Vec<ConversationMessage>,
+}
+
+/// A message in a conversation view. Unread messages and the latest message
+/// start out expanded; the rest are shown collapsed.
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversationMessage {
+    pub email: Email,
+    pub expanded: bool,
+}
+
+impl Conversation {
+    fn new(conversation_id: String, mut emails: Vec<Email>) -> Self {
+        emails.sort_by(|a, b| {
+            a.received_date_time
+                .cmp(&b.received_date_time)
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        let last = emails.len().saturating_sub(1);
+        let messages = emails
+            .into_iter()
+            .enumerate()
+            .map(|(i, email)| ConversationMessage {
+                expanded: !email.is_read || i == last,
+                email,
+            })
+            .collect();
+        Self {
+            conversation_id,
+            messages,
+        }
+    }
+}
+
+/// Groups emails into conversation threads.
+///
+/// Emails are grouped by `conversationId`, in the order each conversation is
+/// first seen. Within a conversation the reply tree is rebuilt from the
+/// `conversationIndex`: every reply appends one block to its parent's index.
+pub fn group_into_threads(emails: Vec<Email>) -> Vec<Thread> {
+    let mut order = Vec::new();
+    let mut conversations: HashMap<String, Vec<(Vec<u8>, Email)>> = HashMap::new();
+
+    for email in emails {
+        let index = base64::decode(&email.conversation_index).unwrap_or_default();
+        let entries = conversations
+            .entry(email.conversation_id.clone())
+            .or_insert_with(|| {
+                order.push(email.conversation_id.clone());
+                Vec::new()
+            });
+        entries.push((index, email));
+    }
+
+    order
+        .into_iter()
+        .map(|conversation_id| {
+            let entries = conversations.remove(&conversation_id).unwrap_or_default();
+            Thread {
+                conversation_id,
+                messages: build_thread_nodes(entries),
+            }
+        })
+        .collect()
+}
+
+fn parent_conversation_index(index: &[u8]) -> Option<&[u8]> {
+    if index.len() > CONVERSATION_INDEX_HEADER_LEN
+        && (index.len() - CONVERSATION_INDEX_HEADER_LEN)
+            .is_multiple_of(CONVERSATION_INDEX_BLOCK_LEN)
+    {
+        Some(&index[..index.len() - CONVERSATION_INDEX_BLOCK_LEN])
+    } else {
+        None
+    }
+}
+
+fn build_thread_nodes(mut entries: Vec<(Vec<u8>, Email)>) -> Vec<ThreadNode> {
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let indexes: HashSet<Vec<u8>> = entries.iter().map(|(index, _)| index.clone()).collect();
+    let (roots, mut rest): (Vec<_>, Vec<_>) = entries.into_iter().partition(|(index, _)| {
+        parent_conversation_index(index)
+            .filter(|parent| indexes.contains(*parent))
+            .is_none()
+    });
+
+    roots
+        .into_iter()
+        .map(|(index, email)| ThreadNode {
+            replies: take_replies(&index, &mut rest),
+            email,
+        })
+        .collect()
+}
+
+fn take_replies(index: &[u8], pool: &mut Vec<(Vec<u8>, Email)>) -> Vec<ThreadNode> {
+    let (children, remaining): (Vec<_>, Vec<_>) = std::mem::take(pool)
+        .into_iter()
+        .partition(|(child, _)| parent_conversation_index(child) == Some(index));
+    *pool = remaining;
+
+    children
+        .into_iter()
+        .map(|(child, email)| ThreadNode {
+            replies: take_replies(&child, pool),
+            email,
+        })
+        .collect()
+}
+
+/// Rewrites `cid:` references in an HTML body into `data:` URIs built from
+/// the matching inline attachments. A reference runs up to the closing quote,
+/// parenthesis or whitespace, so `cid:image1` never matches inside
+/// `cid:image10`. References without a matching attachment are left
+/// untouched.
+pub fn inline_cid_images(html: &str, attachments: &[InlineAttachment]) -> String {
+    let images: HashMap<&str, String> = attachments
+        .iter()
+        .filter(|attachment| attachment.is_inline)
+        .filter_map(|attachment| {
+            let content_id = attachment
+                .content_id
+                .as_deref()?
+                .trim_start_matches('<')
+                .trim_end_matches('>');
+            let content_bytes = attachment.content_bytes.as_deref()?;
+            let content_type = attachment
+                .content_type
+                .as_deref()
+                .unwrap_or("application/octet-stream");
+            Some((
+                content_id,
+                format!("data:{content_type};base64,{content_bytes}"),
+            ))
+        })
+        .collect();
+
+    let mut result = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find("cid:") {
+        let (before, reference) = rest.split_at(start);
+        let end = reference
+            .find(|c: char| matches!(c, '"' | '\'' | ')' | '>') || c.is_whitespace())
+            .unwrap_or(reference.len());
+        let (reference, after) = reference.split_at(end);
+
+        result.push_str(before);
+        match images.get(&reference["cid:".len()..]) {
+            Some(data_uri) => result.push_str(data_uri),
+            None => result.push_str(reference),
+        }
+        rest = after;
+    }
+    result.push_str(rest);
+
+    result
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortField {
+    Date,
+    From,
+    Subject,
+    Importance,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// A sort criterion for email listings, parsed from `field` or `field:order`,
+/// e.g. `date:desc` or `subject`. The order defaults to ascending.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SortCriterion {
+    pub field: SortField,
+    pub order: SortOrder,
+}
+
+impl SortCriterion {
+    /// Returns the Graph `$orderby` expression for this criterion.
+    pub fn to_order_by(self) -> String {
+        let field = match self.field {
+            SortField::Date => "receivedDateTime",
+            SortField::From => "from/emailAddress/name",
+            SortField::Subject => "subject",
+            SortField::Importance => "importance",
+        };
+        let order = match self.order {
+            SortOrder::Asc => "asc",
+            SortOrder::Desc => "desc",
+        };
+        format!("{} {}", field, order)
+    }
+}
+
+impl FromStr for SortCriterion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (field, order) = s.split_once(':').unwrap_or((s, "asc"));
+        let field = match field.to_lowercase().as_str() {
+            "date" => SortField::Date,
+            "from" => SortField::From,
+            "subject" => SortField::Subject,
+            "importance" => SortField::Importance,
+            _ => return Err(format!("invalid sort field: {}", field)),
+        };
+        let order = match order.to_lowercase().as_str() {
+            "asc" => SortOrder::Asc,
+            "desc" => SortOrder::Desc,
+            _ => return Err(format!("invalid sort order: {}", order)),
+        };
+        Ok(Self { field, order })
+    }
+}
+
+/// An internet message header as returned by Graph.
+#[derive(Deserialize, Debug)]
+struct MessageHeader {
+    name: String,
+    value: String,
+}
+
+/// Finds when a message was sent, from its `Date` header, and received, from
+/// the newest `Received` header, falling back to the sent date.
+fn message_dates(headers: &[MessageHeader]) -> (Option<DateTime<Utc>>, Option<DateTime<Utc>>) {
+    let parse = |value: &str| {
+        // Drop trailing comments such as `(UTC)`, which the parser rejects.
+        let value = value.split(" (").next().unwrap_or(value).trim();
+        DateTime::parse_from_rfc2822(value)
+            .ok()
+            .map(|date| date.with_timezone(&Utc))
+    };
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case(name))
+            .map(|header| header.value.as_str())
+    };
+
+    let sent = header("Date").and_then(parse);
+    let received = header("Received")
+        .and_then(|value| value.rsplit_once(';'))
+        .and_then(|(_, date)| parse(date))
+        .or(sent);
+    (sent, received)
+}
+
+/// The result of one sender authentication check.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthVerdict {
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+    None,
+    TempError,
+    PermError,
+    Other,
+}
+
+impl From<&str> for AuthVerdict {
+    fn from(s: &str) -> Self {
+        match s.to_lowercase().as_str() {
+            "pass" | "bestguesspass" => AuthVerdict::Pass,
+            "fail" | "hardfail" => AuthVerdict::Fail,
+            "softfail" => AuthVerdict::SoftFail,
+            "neutral" => AuthVerdict::Neutral,
+            "none" => AuthVerdict::None,
+            "temperror" => AuthVerdict::TempError,
+            "permerror" => AuthVerdict::PermError,
+            _ => AuthVerdict::Other,
+        }
+    }
+}
+
+/// SPF, DKIM and DMARC verdicts recorded by the receiving server, plus
+/// Microsoft's composite authentication verdict when present. Checks the
+/// server did not report are `None`.
+#[derive(Serialize, Debug, Default, PartialEq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthResults {
+    pub spf: Option<AuthVerdict>,
+    pub dkim: Option<AuthVerdict>,
+    pub dmarc: Option<AuthVerdict>,
+    pub compauth: Option<AuthVerdict>,
+}
+
+impl AuthResults {
+    /// Reads the topmost `Authentication-Results` header, the one added by
+    /// the mailbox's own server, falling back to `Received-SPF` for SPF.
+    fn from_headers(headers: &[MessageHeader]) -> Self {
+        let mut results = AuthResults::default();
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case(name))
+                .map(|header| header.value.as_str())
+        };
+
+        if let Some(value) = header("Authentication-Results") {
+            for check in value.split(';') {
+                let Some((method, verdict)) = check.trim().split_once('=') else {
+                    continue;
+                };
+                let verdict = verdict.split_whitespace().next().unwrap_or_default();
+                let slot = match method.trim().to_lowercase().as_str() {
+                    "spf" => &mut results.spf,
+                    "dkim" => &mut results.dkim,
+                    "dmarc" => &mut results.dmarc,
+                    "compauth" => &mut results.compauth,
+                    _ => continue,
+                };
+                slot.get_or_insert(AuthVerdict::from(verdict));
+            }
+        }
+
+        if results.spf.is_none() {
+            results.spf = header("Received-SPF")
+                .and_then(|value| value.split_whitespace().next())
+                .map(AuthVerdict::from);
+        }
+        results
+    }
+}
+
+/// Appends the `$orderby` query option for `sort` to a Graph collection URL.
+fn with_order_by(url: String, sort: Option<SortCriterion>) -> String {
+    match sort {
+        Some(sort) => format!("{}?$orderby={}", url, sort.to_order_by()),
+        None => url,
+    }
+}
+
+/// Options for listing one page of emails.
+#[derive(Debug, Default)]
+pub struct ListOptions {
+    /// Maximum number of emails per page.
+    pub limit: Option<u32>,
+    pub sort: Option<SortCriterion>,
+    /// Only read (`false`) or unread (`true`) emails.
+    pub unread: Option<bool>,
+    /// Only flagged (`true`) or not flagged (`false`) emails.
+    pub flagged: Option<bool>,
+    /// A search query in Graph's KQL syntax, e.g. `from:alice subject:report`.
+    pub search: Option<String>,
+}
+
+/// A `$filter` clause that matches every message, put first when sorting a
+/// filtered listing by date, since Graph requires the `$orderby` property to
+/// lead the `$filter` expression.
+const ALL_RECEIVED_FILTER: &str = "receivedDateTime ge 1900-01-01T00:00:00Z";
+
+impl ListOptions {
+    /// Checks that Graph can serve this combination of options. A search
+    /// can't be combined with filters or sorting, and filtered listings can
+    /// only be sorted by date.
+    pub fn validate(&self) -> Result<(), String> {
+        let filtered = self.unread.is_some() || self.flagged.is_some();
+        if self.search.is_some() && (filtered || self.sort.is_some()) {
+            return Err("search can't be combined with filters or sort".to_string());
+        }
+        match self.sort {
+            Some(sort) if filtered && sort.field != SortField::Date => {
+                Err("filtered listings can only be sorted by date".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn query_params(&self) -> Vec<(&'static str, String)> {
+        let mut params = Vec::new();
+
+        if let Some(limit) = self.limit {
+            params.push(("$top", limit.to_string()));
+        }
+
+        let mut filters = Vec::new();
+        if self.sort.is_some() && (self.unread.is_some() || self.flagged.is_some()) {
+            filters.push(ALL_RECEIVED_FILTER.to_string());
+        }
+        if let Some(unread) = self.unread {
+            filters.push(format!("isRead eq {}", !unread));
+        }
+        if let Some(flagged) = self.flagged {
+            let op = if flagged { "eq" } else { "ne" };
+            filters.push(format!("flag/flagStatus {} 'flagged'", op));
+        }
+        if !filters.is_empty() {
+            params.push(("$filter", filters.join(" and ")));
+        }
+
+        if let Some(sort) = self.sort {
+            params.push(("$orderby", sort.to_order_by()));
+        }
+
+        // Graph does not support $count together with $search.
+        match &self.search {
+            Some(search) => params.push(("$search", format!("\"{}\"", search.replace('"', "")))),
+            None => params.push(("$count", "true".to_string())),
+        }
+
+        params
+    }
+}
+
+/// One page of an email listing. `next_cursor` is passed back to fetch the
+/// following page and is absent on the last one.
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailPage {
+    pub emails: Vec<Email>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_count: Option<u64>,
+    pub next_cursor: Option<String>,
+}
+
+/// One page of changes to a folder since a previous sync. While
+/// `next_cursor` is present there are more changes to fetch; the last page
+/// carries a `delta_cursor` instead, to be passed on the next sync.
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailDelta {
+    pub emails: Vec<Email>,
+    pub removed: Vec<String>,
+    pub next_cursor: Option<String>,
+    pub delta_cursor: Option<String>,
+}
+
+impl EmailDelta {
+    fn from_graph_json(json: &Value) -> Result<Self, GraphClientError> {
+        let (emails, removed) = parse_delta_items(json)?;
+        Ok(Self {
+            emails,
+            removed,
+            next_cursor: json["@odata.nextLink"].as_str().map(encode_cursor),
+            delta_cursor: json["@odata.deltaLink"].as_str().map(encode_cursor),
+        })
+    }
+}
+
+/// One page of changes to the folder hierarchy since a previous sync, paged
+/// like `EmailDelta`.
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderDelta {
+    pub folders: Vec<Folder>,
+    pub removed: Vec<String>,
+    pub next_cursor: Option<String>,
+    pub delta_cursor: Option<String>,
+}
+
+impl FolderDelta {
+    fn from_graph_json(json: &Value) -> Result<Self, GraphClientError> {
+        let (folders, removed) = parse_delta_items(json)?;
+        Ok(Self {
+            folders,
+            removed,
+            next_cursor: json["@odata.nextLink"].as_str().map(encode_cursor),
+            delta_cursor: json["@odata.deltaLink"].as_str().map(encode_cursor),
+        })
+    }
+}
+
+/// Splits the items of a Graph delta page into the changed items and the ids
+/// of the removed ones.
+fn parse_delta_items<T: DeserializeOwned>(
+    json: &Value,
+) -> Result<(Vec<T>, Vec<String>), GraphClientError> {
+    let values = json["value"]
+        .as_array()
+        .ok_or_else(|| GraphClientError::Parse("delta", json.clone()))?;
+
+    let mut items = Vec::new();
+    let mut removed = Vec::new();
+    for value in values {
+        if value.get("@removed").is_some() {
+            let id = value["id"]
+                .as_str()
+                .ok_or_else(|| GraphClientError::Parse("delta", value.clone()))?;
+            removed.push(id.to_string());
+        } else {
+            items.push(serde_json::from_value(value.clone())?);
+        }
+    }
+    Ok((items, removed))
+}
+
+/// Shortest lifetime, in minutes, given to a change notification
+/// subscription.
+const MIN_SUBSCRIPTION_MINUTES: i64 = 45;
+
+/// Longest lifetime, in minutes, given to a change notification subscription.
+/// Graph allows up to 10080 minutes for messages; a little is left for clock
+/// skew.
+const MAX_SUBSCRIPTION_MINUTES: i64 = 10_070;
+
+/// A Graph change notification subscription. Until it expires, Graph POSTs a
+/// notification to `notification_url` for every change to the subscribed
+/// messages, carrying `client_state` so the receiver can check where it came
+/// from. Subscriptions have to be renewed before they expire.
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Subscription {
+    pub id: String,
+    pub resource: String,
+    /// Comma-separated list of `created`, `updated` and `deleted`.
+    pub change_type: String,
+    pub notification_url: String,
+    pub expiration_date_time: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_state: Option<String>,
+}
+
+/// Returns when a subscription asked to last `minutes` should expire,
+/// defaulting to and capped at the longest lifetime Graph allows.
+pub fn subscription_expiration(now: DateTime<Utc>, minutes: Option<i64>) -> DateTime<Utc> {
+    let minutes = minutes
+        .unwrap_or(MAX_SUBSCRIPTION_MINUTES)
+        .clamp(MIN_SUBSCRIPTION_MINUTES, MAX_SUBSCRIPTION_MINUTES);
+    now + chrono::Duration::minutes(minutes)
+}
+
+/// Turns a Graph `@odata.nextLink` into an opaque pagination cursor.
+fn encode_cursor(next_link: &str) -> String {
+    encode_config(next_link, URL_SAFE_NO_PAD)
+}
+
+/// Turns a pagination cursor back into a Graph URL, refusing anything that
+/// does not point at the Graph API so the access token is never sent
+/// elsewhere.
+fn decode_cursor(cursor: &str) -> Result<String, GraphClientError> {
+    let bytes =
+        decode_config(cursor, URL_SAFE_NO_PAD).map_err(|_| GraphClientError::InvalidCursor)?;
+    let url = String::from_utf8(bytes).map_err(|_| GraphClientError::InvalidCursor)?;
+    if url.starts_with(&format!("{}/", GRAPH_API_BASE_URL)) {
+        Ok(url)
+    } else {
+        Err(GraphClientError::InvalidCursor)
+    }
+}
+
+/// Translates a logical folder name into the Graph well-known folder name,
+/// which can be used in place of a folder id. Folder lookups fall back to it
+/// when no top-level folder has the name, so the standard folders resolve
+/// regardless of the mailbox language while a real folder named e.g. `Spam`
+/// stays reachable.
+pub fn well_known_folder_name(folder_name: &str) -> Option<&'static str> {
+    match folder_name.to_lowercase().as_str() {
+        "inbox" => Some("inbox"),
+        "sent" | "sentitems" | "sent items" => Some("sentitems"),
+        "drafts" => Some("drafts"),
+        "trash" | "deleteditems" | "deleted items" => Some("deleteditems"),
+        "junk" | "spam" | "junkemail" | "junk email" => Some("junkemail"),
+        "archive" => Some("archive"),
+        "outbox" => Some("outbox"),
+        _ => None,
+    }
+}
+
+/// Expands an account's alias in the first segment of a folder path, so an
+/// alias for `sent` also resolves `sent/2023`. Aliases are keyed in lowercase.
+fn expand_folder_alias(aliases: &HashMap<String, String>, path: &str) -> Option<String> {
+    let (first, rest) = match path.split_once('/') {
+        Some((first, rest)) => (first, Some(rest)),
+        None => (path, None),
+    };
+    let folder = aliases.get(&first.to_lowercase())?;
+    Some(match rest {
+        Some(rest) => format!("{}/{}", folder.trim_end_matches('/'), rest),
+        None => folder.clone(),
+    })
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct UploadSession {
+    upload_url: String,
+}
+
+/// Largest number of requests Graph accepts in a single JSON batch.
+const GRAPH_BATCH_LIMIT: usize = 20;
+
+struct BatchRequest {
+    email_id: String,
+    method: Method,
+    url: String,
+    body: Option<Value>,
+}
+
+/// A message reference fetched with `$select=id`.
+#[derive(Deserialize, Debug)]
+struct EmailId {
+    id: String,
+}
+
+/// A message's folder, fetched with `$select=parentFolderId`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EmailFolder {
+    parent_folder_id: String,
+}
+
+/// An email moved to Deleted Items, along with the folder it was deleted
+/// from so a client can undo the delete by moving it back.
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DeletedEmail {
+    #[serde(flatten)]
+    pub email: Email,
+    pub previous_folder_id: String,
+}
+
+/// The fields needed to spot duplicate messages, fetched without bodies.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct MessageIdentity {
+    id: String,
+    internet_message_id: Option<String>,
+    received_date_time: String,
+}
+
+/// A message whose `Message-Id` matches an earlier message in the folder.
+#[derive(Serialize, Debug, PartialEq, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Duplicate {
+    pub id: String,
+    pub internet_message_id: String,
+    pub original_id: String,
+}
+
+/// Duplicates found in a folder and, unless it was a dry run, the outcome of
+/// moving each of them to Deleted Items.
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DedupeReport {
+    pub duplicates: Vec<Duplicate>,
+    pub results: Vec<BatchResult>,
+}
+
+/// Groups messages by `Message-Id` and returns every message but the earliest
+/// received one in each group. Messages without an id are never duplicates.
+fn find_duplicates(mut messages: Vec<MessageIdentity>) -> Vec<Duplicate> {
+    messages.sort_by(|a, b| {
+        a.received_date_time
+            .cmp(&b.received_date_time)
+            .then_with(|| a.id.cmp(&b.id))
+    });
+
+    let mut originals: HashMap<String, String> = HashMap::new();
+    let mut duplicates = Vec::new();
+    for message in messages {
+        let Some(internet_message_id) = message.internet_message_id else {
+            continue;
+        };
+        match originals.get(&internet_message_id) {
+            Some(original_id) => duplicates.push(Duplicate {
+                id: message.id,
+                internet_message_id,
+                original_id: original_id.clone(),
+            }),
+            None => {
+                originals.insert(internet_message_id, message.id);
+            }
+        }
+    }
+    duplicates
+}
+
+/// Formats a message as an mboxrd entry: a `From ` separator line dated
+/// with the received time, the message with CRLF line endings converted, and
+/// any line starting with `>*From ` quoted with one more `>`.
+fn mbox_entry(mime: &[u8], received_date_time: &str) -> Vec<u8> {
+    let date = DateTime::parse_from_rfc3339(received_date_time)
+        .map(|date| date.format("%a %b %e %H:%M:%S %Y").to_string())
+        .unwrap_or_else(|_| "Thu Jan  1 00:00:00 1970".to_string());
+
+    let mut entry = format!("From MAILER-DAEMON {}\n", date).into_bytes();
+    let mime = mime.strip_suffix(b"\n").unwrap_or(mime);
+    for line in mime.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let unquoted = &line[line.iter().take_while(|&&b| b == b'>').count()..];
+        if unquoted.starts_with(b"From ") {
+            entry.push(b'>');
+        }
+        entry.extend_from_slice(line);
+        entry.push(b'\n');
+    }
+    entry.push(b'\n');
+    entry
+}
+
+/// Base64-encodes a byte stream on the fly, carrying the bytes that don't
+/// fill a whole 3-byte group over to the next chunk.
+fn base64_stream<S, E>(stream: S) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    let mut carry = Vec::new();
+    stream
+        .map(Some)
+        .chain(stream::iter([None]))
+        .map(move |chunk| match chunk {
+            Some(Ok(chunk)) => {
+                carry.extend_from_slice(&chunk);
+                let whole = carry.len() / 3 * 3;
+                let encoded = base64::encode(&carry[..whole]);
+                carry.drain(..whole);
+                Ok(Bytes::from(encoded))
+            }
+            Some(Err(err)) => Err(err),
+            None => Ok(Bytes::from(base64::encode(&carry))),
+        })
+}
+
+/// The state an imported message is given.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ImportFlags {
+    pub is_read: bool,
+    pub is_flagged: bool,
+}
+
+/// The message fields copied from the draft Graph parses an import into.
+const IMPORTED_FIELDS: &str = "subject,body,from,sender,toRecipients,ccRecipients,bccRecipients,replyTo,importance,internetMessageId,internetMessageHeaders";
+
+/// The outcome of one request in a batch operation.
+#[derive(Serialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchResult {
+    pub id: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<Email>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl BatchResult {
+    fn new(email_id: &str, response: Option<&Value>) -> Self {
+        let Some(response) = response else {
+            return Self {
+                id: email_id.to_string(),
+                status: 0,
+                email: None,
+                error: Some("Missing response in batch".to_string()),
+            };
+        };
+
+        let status = response["status"].as_u64().unwrap_or_default() as u16;
+        if (200..300).contains(&status) {
+            Self {
+                id: email_id.to_string(),
+                status,
+                email: serde_json::from_value(response["body"].clone()).ok(),
+                error: None,
+            }
+        } else {
+            let error = response["body"]["error"]["message"]
+                .as_str()
+                .map(ToString::to_string)
+                .unwrap_or_else(|| format!("Request failed with status: {}", status));
+            Self {
+                id: email_id.to_string(),
+                status,
+                email: None,
+                error: Some(error),
+            }
+        }
+    }
+}
+
+/// Network settings for the HTTP client used to talk to Graph.
+#[derive(Clone, Debug)]
+pub struct HttpConfig {
+    /// Maximum time to establish a connection.
+    pub connect_timeout: Duration,
+    /// Maximum time for a whole request, from connecting until the response
+    /// body has been read. Downloads streamed on to API clients are exempt,
+    /// since their bodies are read at the API client's pace.
+    pub timeout: Duration,
+    /// Proxy to route Graph traffic through, e.g. `http://proxy:3128` or
+    /// `socks5h://127.0.0.1:9050` (the `h` resolves hostnames on the proxy,
+    /// as needed for Tor).
+    pub proxy: Option<String>,
+}
+
+impl HttpConfig {
+    pub fn build_client(&self) -> reqwest::Result<HttpClient> {
+        let mut builder = Client::builder().connect_timeout(self.connect_timeout);
+
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+
+        // Prefer rustls when it is enabled, even if native-tls is also built in.
+        #[cfg(feature = "rustls")]
+        let builder = builder.use_rustls_tls();
+
+        Ok(HttpClient {
+            client: builder.build()?,
+            timeout: Some(self.timeout),
+        })
+    }
+}
+
+/// An HTTP client for Graph along with its request timeout. Clones share the
+/// same connection pool.
+#[derive(Clone, Debug)]
+pub struct HttpClient {
+    client: Client,
+    timeout: Option<Duration>,
+}
+
+/// Tokens issued by the Microsoft identity platform.
+#[derive(Debug, Deserialize)]
+pub struct TokenGrant {
+    pub access_token: String,
+    /// A new refresh token. Microsoft may keep the previous one valid
+    /// instead of issuing another.
+    pub refresh_token: Option<String>,
+}
+
+impl HttpClient {
+    /// Exchanges a refresh token for a new access token.
+    pub async fn refresh_token(
+        &self,
+        client_id: &str,
+        client_secret: Option<&str>,
+        refresh_token: &str,
+    ) -> Result<TokenGrant, GraphClientError> {
+        let mut form = vec![
+            ("client_id", client_id),
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+            ("scope", SCOPES),
+        ];
+        if let Some(client_secret) = client_secret {
+            form.push(("client_secret", client_secret));
+        }
+
+        let mut request = self.client.post(TOKEN_URL).form(&form);
+        if let Some(timeout) = self.timeout {
+            request = request.timeout(timeout);
+        }
+        let response = request.send().await?;
+
+        if response.status().is_success() {
+            Ok(response.json().await?)
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+}
+
+pub struct GraphClient {
+    http: HttpClient,
+    access_token: String,
+    folder_cache: HashMap<String, String>,
+    folder_aliases: HashMap<String, String>,
+}
+
+impl GraphClient {
+    /// Creates a client that shares an existing HTTP client, and with it its
+    /// connection pool and network settings.
+    pub fn with_http_client(http: HttpClient, access_token: String) -> Self {
+        Self {
+            http,
+            access_token,
+            folder_cache: HashMap::new(),
+            folder_aliases: HashMap::new(),
+        }
+    }
+
+    /// Sets the account's folder aliases, mapping logical names such as
+    /// `sent` to the folder paths that hold them in this mailbox.
+    pub fn with_folder_aliases(
+        mut self,
+        aliases: impl IntoIterator<Item = (String, String)>,
+    ) -> Self {
+        self.folder_aliases = aliases
+            .into_iter()
+            .map(|(alias, folder)| (alias.to_lowercase(), folder))
+            .collect();
+        self
+    }
+
+    /// Expands an alias at the start of a folder path, if there is one.
+    fn resolve_folder_path(&self, path: &str) -> String {
+        expand_folder_alias(&self.folder_aliases, path).unwrap_or_else(|| path.to_string())
+    }
+
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        self.http
+            .client
+            .request(method, url)
+            .bearer_auth(&self.access_token)
+            .header("Prefer", PREFER_IMMUTABLE_ID)
+    }
+
+    /// Sends a request to Graph within the configured timeout.
+    async fn send(&self, request: RequestBuilder) -> Result<Response, GraphClientError> {
+        match self.http.timeout {
+            Some(timeout) => self.execute(request.timeout(timeout)).await,
+            None => self.execute(request).await,
+        }
+    }
+
+    /// Sends a request whose response body is streamed on to the API client.
+    /// Only the connect timeout applies, since the body is read at the API
+    /// client's pace.
+    async fn send_streaming(&self, request: RequestBuilder) -> Result<Response, GraphClientError> {
+        self.execute(request).await
+    }
+
+    /// Sends a request to Graph, logging its method, URL, status and timing,
+    /// and recording them as metrics.
+    async fn execute(&self, request: RequestBuilder) -> Result<Response, GraphClientError> {
+        let request = request.build()?;
+        let method = request.method().clone();
+        let url = request.url().clone();
+        let start = Instant::now();
+
+        let response = self.http.client.execute(request).await;
+        let elapsed = start.elapsed();
+        let status = match &response {
+            Ok(response) => response.status().as_str().to_string(),
+            Err(_) => "error".to_string(),
+        };
+        counter!("graph_requests_total", 1, "method" => method.to_string(), "status" => status);
+        histogram!("graph_request_duration_seconds", elapsed, "method" => method.to_string());
+
+        let response = response?;
+        if let Some(content_length) = response.content_length() {
+            counter!("graph_response_bytes_total", content_length);
+        }
+        debug!(
+            %method,
+            %url,
+            status = %response.status(),
+            elapsed_ms = elapsed.as_millis() as u64,
+            content_length = ?response.content_length(),
+            "graph request"
+        );
+        Ok(response)
+    }
+
+    fn get(&self, url: &str) -> RequestBuilder {
+        self.request(Method::GET, url)
+    }
+
+    fn post(&self, url: &str) -> RequestBuilder {
+        self.request(Method::POST, url)
+    }
+
+    fn patch(&self, url: &str) -> RequestBuilder {
+        self.request(Method::PATCH, url)
+    }
+
+    fn delete(&self, url: &str) -> RequestBuilder {
+        self.request(Method::DELETE, url)
+    }
+
+    pub async fn get_user_folders(&self) -> Result<Vec<Folder>, GraphClientError> {
+        let url = format!("{}/me/mailFolders", GRAPH_API_BASE_URL);
+        self.fetch_all_items::<Folder>(&url).await
+    }
+
+    /// Creates a folder from a `/`-separated path, creating any missing parent
+    /// folders along the way. Existing folders on the path are reused, and the
+    /// first segment may be an alias or a standard folder name like `Inbox`.
+    pub async fn create_folder(&self, path: &str) -> Result<Folder, GraphClientError> {
+        let path = self.resolve_folder_path(path);
+        let mut folder: Option<Folder> = None;
+
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let parent_id = folder.as_ref().map(|folder| folder.id.clone());
+            let existing = match parent_id.as_deref() {
+                Some(parent_id) => self.find_child_folder(parent_id, name).await?,
+                None => self.find_root_folder(name).await?,
+            };
+
+            folder = Some(match existing {
+                Some(existing) => existing,
+                None => self.create_child_folder(parent_id.as_deref(), name).await?,
+            });
+        }
+
+        folder.ok_or(GraphClientError::InvalidFolderName(path))
+    }
+
+    async fn create_child_folder(
+        &self,
+        parent_id: Option<&str>,
+        name: &str,
+    ) -> Result<Folder, GraphClientError> {
+        let url = match parent_id {
+            Some(parent_id) => format!(
+                "{}/me/mailFolders/{}/childFolders",
+                GRAPH_API_BASE_URL, parent_id
+            ),
+            None => format!("{}/me/mailFolders", GRAPH_API_BASE_URL),
+        };
+        let payload = json!({ "displayName": name });
+        let response = self.send(self.post(&url).json(&payload)).await?;
+
+        if response.status().is_success() {
+            let folder: Folder = response.json().await?;
+            Ok(folder)
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    /// Finds a top-level hidden folder by display name, creating it if the
+    /// mailbox doesn't have one. Hidden folders are left out of folder
+    /// listings in mail clients, though they can still be opened by id.
+    pub async fn get_or_create_hidden_folder(
+        &self,
+        name: &str,
+    ) -> Result<Folder, GraphClientError> {
+        let url = format!(
+            "{}/me/mailFolders?includeHiddenFolders=true",
+            GRAPH_API_BASE_URL
+        );
+        let existing = self
+            .fetch_all_items::<Folder>(&url)
+            .await?
+            .into_iter()
+            .find(|f| f.display_name.to_lowercase() == name.to_lowercase());
+        if let Some(folder) = existing {
+            return Ok(folder);
+        }
+
+        let url = format!("{}/me/mailFolders", GRAPH_API_BASE_URL);
+        let payload = json!({ "displayName": name, "isHidden": true });
+        let response = self.send(self.post(&url).json(&payload)).await?;
+
+        if response.status().is_success() {
+            let folder: Folder = response.json().await?;
+            Ok(folder)
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    /// Renames a folder in place, keeping its position in the hierarchy.
+    pub async fn rename_folder(
+        &mut self,
+        folder_name: &str,
+        new_name: &str,
+    ) -> Result<Folder, GraphClientError> {
+        if new_name.is_empty() || new_name.contains('/') {
+            return Err(GraphClientError::InvalidFolderName(new_name.to_string()));
+        }
+
+        let folder_id = self.get_folder_id_by_name(folder_name).await?;
+        let url = format!("{}/me/mailFolders/{}", GRAPH_API_BASE_URL, folder_id);
+        let payload = json!({ "displayName": new_name });
+        let response = self.send(self.patch(&url).json(&payload)).await?;
+
+        if response.status().is_success() {
+            self.forget_folder(folder_name);
+            let folder: Folder = response.json().await?;
+            Ok(folder)
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    /// Deletes a folder, with its emails and child folders, by moving it to
+    /// the Deleted Items folder.
+    pub async fn delete_folder(&mut self, folder_name: &str) -> Result<(), GraphClientError> {
+        let folder_id = self.get_folder_id_by_name(folder_name).await?;
+        let url = format!("{}/me/mailFolders/{}", GRAPH_API_BASE_URL, folder_id);
+        let response = self.send(self.delete(&url)).await?;
+
+        if response.status().is_success() {
+            self.forget_folder(folder_name);
+            Ok(())
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    /// Drops a folder and its child folders from the folder id cache.
+    fn forget_folder(&mut self, folder_name: &str) {
+        let folder_name = self.resolve_folder_path(folder_name).to_lowercase();
+        let prefix = format!("{}/", folder_name);
+        self.folder_cache.retain(|name, _| {
+            let name = name.to_lowercase();
+            name != folder_name && !name.starts_with(&prefix)
+        });
+    }
+
+    pub async fn get_folder_status_by_name(
+        &mut self,
+        folder_name: &str,
+    ) -> Result<FolderStatus, GraphClientError> {
+        let folder_id = self.get_folder_id_by_name(folder_name).await?;
+        let url = format!("{}/me/mailFolders/{}", GRAPH_API_BASE_URL, folder_id);
+        let response = self.send(self.get(&url)).await?;
+
+        if response.status().is_success() {
+            let folder: Folder = response.json().await?;
+            Ok(folder.into())
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    pub async fn get_folders_status(&self) -> Result<Vec<FolderStatus>, GraphClientError> {
+        let folders = self.get_user_folders().await?;
+        Ok(folders.into_iter().map(FolderStatus::from).collect())
+    }
+
+    pub async fn get_user_emails(
+        &self,
+        sort: Option<SortCriterion>,
+    ) -> Result<Vec<Email>, GraphClientError> {
+        let url = with_order_by(format!("{}/me/messages", GRAPH_API_BASE_URL), sort);
+        self.fetch_all_items::<Email>(&url).await
+    }
+
+    pub async fn get_user_emails_paginated(
+        &self,
+        initial_page: usize,
+        num_pages: usize,
+    ) -> Result<(Vec<Email>, bool), GraphClientError> {
+        let url = format!("{}/me/messages", GRAPH_API_BASE_URL);
+        self.fetch_pages::<Email>(&url, initial_page, num_pages)
+            .await
+    }
+
+    pub async fn get_user_emails_from_folder(
+        &self,
+        folder_id: &str,
+        sort: Option<SortCriterion>,
+    ) -> Result<Vec<Email>, GraphClientError> {
+        let url = with_order_by(
+            format!(
+                "{}/me/mailFolders/{}/messages",
+                GRAPH_API_BASE_URL, folder_id
+            ),
+            sort,
+        );
+        let response = self.send(self.get(&url)).await?;
+
+        if response.status().is_success() {
+            let json: Value = response.json().await?;
+            let emails_value = json["value"]
+                .as_array()
+                .ok_or_else(|| GraphClientError::Parse("emails", json.clone()))?;
+
+            let emails: Result<Vec<Email>, serde_json::Error> = emails_value
+                .iter()
+                .map(|email_value| serde_json::from_value(email_value.clone()))
+                .collect();
+
+            Ok(emails?)
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    /// Lists one page of emails from a folder. Without a cursor the first page
+    /// is fetched using `options`; with a cursor the page it points to is
+    /// fetched and `options` are ignored, since they are encoded in it.
+    pub async fn get_folder_emails_page(
+        &mut self,
+        folder_name: &str,
+        options: &ListOptions,
+        cursor: Option<&str>,
+    ) -> Result<EmailPage, GraphClientError> {
+        let request = match cursor {
+            Some(cursor) => self.get(&decode_cursor(cursor)?),
+            None => {
+                let folder_id = self.get_folder_id_by_name(folder_name).await?;
+                let url = format!(
+                    "{}/me/mailFolders/{}/messages",
+                    GRAPH_API_BASE_URL, folder_id
+                );
+                self.get(&url).query(&options.query_params())
+            }
+        };
+        let response = self.send(request).await?;
+
+        if response.status().is_success() {
+            let json: Value = response.json().await?;
+            let emails_value = json["value"]
+                .as_array()
+                .ok_or_else(|| GraphClientError::Parse("emails", json.clone()))?;
+
+            let emails = emails_value
+                .iter()
+                .map(|email_value| serde_json::from_value(email_value.clone()))
+                .collect::<Result<Vec<Email>, _>>()?;
+
+            Ok(EmailPage {
+                emails,
+                total_count: json["@odata.count"].as_u64(),
+                next_cursor: json["@odata.nextLink"].as_str().map(encode_cursor),
+            })
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    /// Fetches one page of message changes in a folder. Without a cursor this
+    /// starts a full sync, returning every message; with a next or delta
+    /// cursor from an earlier page it continues from there.
+    pub async fn get_folder_delta(
+        &mut self,
+        folder_name: &str,
+        cursor: Option<&str>,
+    ) -> Result<EmailDelta, GraphClientError> {
+        let url = match cursor {
+            Some(cursor) => decode_cursor(cursor)?,
+            None => {
+                let folder_id = self.get_folder_id_by_name(folder_name).await?;
+                format!(
+                    "{}/me/mailFolders/{}/messages/delta",
+                    GRAPH_API_BASE_URL, folder_id
+                )
+            }
+        };
+        let response = self.send(self.get(&url)).await?;
+
+        if response.status().is_success() {
+            let json: Value = response.json().await?;
+            EmailDelta::from_graph_json(&json)
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    /// Fetches one page of changes to the folder hierarchy, cursored like
+    /// `get_folder_delta`.
+    pub async fn get_folders_delta(
+        &self,
+        cursor: Option<&str>,
+    ) -> Result<FolderDelta, GraphClientError> {
+        let url = match cursor {
+            Some(cursor) => decode_cursor(cursor)?,
+            None => format!("{}/me/mailFolders/delta", GRAPH_API_BASE_URL),
+        };
+        let response = self.send(self.get(&url)).await?;
+
+        if response.status().is_success() {
+            let json: Value = response.json().await?;
+            FolderDelta::from_graph_json(&json)
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    /// Lists the change notification subscriptions of the signed-in user.
+    pub async fn list_subscriptions(&self) -> Result<Vec<Subscription>, GraphClientError> {
+        let url = format!("{}/subscriptions", GRAPH_API_BASE_URL);
+        self.fetch_all_items::<Subscription>(&url).await
+    }
+
+    /// Subscribes `notification_url` to changes of the messages in a folder.
+    /// Graph first checks that the URL answers its validation request.
+    pub async fn create_subscription(
+        &mut self,
+        folder_name: &str,
+        notification_url: &str,
+        change_type: &str,
+        client_state: Option<&str>,
+        expiration: DateTime<Utc>,
+    ) -> Result<Subscription, GraphClientError> {
+        let folder_id = self.get_folder_id_by_name(folder_name).await?;
+        let url = format!("{}/subscriptions", GRAPH_API_BASE_URL);
+        let mut payload = json!({
+            "changeType": change_type,
+            "notificationUrl": notification_url,
+            "resource": format!("me/mailFolders('{}')/messages", folder_id),
+            "expirationDateTime": expiration,
+        });
+        if let Some(client_state) = client_state {
+            payload["clientState"] = json!(client_state);
+        }
+
+        let response = self.send(self.post(&url).json(&payload)).await?;
+
+        if response.status().is_success() {
+            Ok(response.json().await?)
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    /// Extends a subscription until `expiration`.
+    pub async fn renew_subscription(
+        &self,
+        subscription_id: &str,
+        expiration: DateTime<Utc>,
+    ) -> Result<Subscription, GraphClientError> {
+        let url = format!("{}/subscriptions/{}", GRAPH_API_BASE_URL, subscription_id);
+        let payload = json!({ "expirationDateTime": expiration });
+        let response = self.send(self.patch(&url).json(&payload)).await?;
+
+        if response.status().is_success() {
+            Ok(response.json().await?)
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    pub async fn delete_subscription(&self, subscription_id: &str) -> Result<(), GraphClientError> {
+        let url = format!("{}/subscriptions/{}", GRAPH_API_BASE_URL, subscription_id);
+        let response = self.send(self.delete(&url)).await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    pub async fn get_user_emails_from_folder_by_name(
+        &mut self,
+        folder_name: &str,
+        sort: Option<SortCriterion>,
+    ) -> Result<Vec<Email>, GraphClientError> {
+        let folder_id = self.get_folder_id_by_name(folder_name).await?;
+        self.get_user_emails_from_folder(&folder_id, sort).await
+    }
+
+    /// Groups every email in a folder into threads. All pages are fetched, so
+    /// replies are never split from parents that fall on a later page.
+    pub async fn get_user_threads_from_folder_by_name(
+        &mut self,
+        folder_name: &str,
+    ) -> Result<Vec<Thread>, GraphClientError> {
+        let folder_id = self.get_folder_id_by_name(folder_name).await?;
+        let url = format!(
+            "{}/me/mailFolders/{}/messages?$top=100",
+            GRAPH_API_BASE_URL, folder_id
+        );
+        let emails = self.fetch_all_items::<Email>(&url).await?;
+        Ok(group_into_threads(emails))
+    }
+
+    /// Collects every message in the conversation an email belongs to, across
+    /// all folders.
+    pub async fn get_conversation(&self, email_id: &str) -> Result<Conversation, GraphClientError> {
+        let email = self.get_email_by_id(email_id).await?;
+        let filter = format!(
+            "conversationId eq '{}'",
+            email.conversation_id.replace('\'', "''")
+        );
+        let url = format!(
+            "{}/me/messages?$filter={}&$top=100",
+            GRAPH_API_BASE_URL,
+            form_urlencoded::byte_serialize(filter.as_bytes())
+                .collect::<String>()
+                .replace('+', "%20")
+        );
+        let emails = self.fetch_all_items::<Email>(&url).await?;
+        Ok(Conversation::new(email.conversation_id, emails))
+    }
+
+    pub async fn get_email_by_id(&self, email_id: &str) -> Result<Email, GraphClientError> {
+        let url = format!("{}/me/messages/{}", GRAPH_API_BASE_URL, email_id);
+        let response = self.send(self.get(&url)).await?;
+
+        if response.status().is_success() {
+            let email: Email = response.json().await?;
+            Ok(email)
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    /// Fetches an email with inline `cid:` images embedded as `data:` URIs, so
+    /// the HTML body can be rendered without further requests. Only inline
+    /// attachments are downloaded, and those larger than `max_size` bytes are
+    /// left as `cid:` references.
+    pub async fn get_email_with_inline_images(
+        &self,
+        email_id: &str,
+        max_size: u64,
+    ) -> Result<Email, GraphClientError> {
+        let mut email = self.get_email_by_id(email_id).await?;
+        if email.body.content_type != "html" || !email.body.content.contains("cid:") {
+            return Ok(email);
+        }
+
+        let url = format!(
+            "{}/me/messages/{}/attachments?$filter=isInline eq true&$select=id,name,contentType,size,isInline",
+            GRAPH_API_BASE_URL, email_id
+        );
+        let mut attachments = Vec::new();
+        for attachment in self.fetch_all_items::<Attachment>(&url).await? {
+            if attachment.size > max_size {
+                continue;
+            }
+
+            let url = format!(
+                "{}/me/messages/{}/attachments/{}",
+                GRAPH_API_BASE_URL, email_id, attachment.id
+            );
+            let response = self.send(self.get(&url)).await?;
+            if !response.status().is_success() {
+                return Err(GraphClientError::Request(response.status()));
+            }
+            attachments.push(response.json::<InlineAttachment>().await?);
+        }
+        email.body.content = inline_cid_images(&email.body.content, &attachments);
+
+        Ok(email)
+    }
+
+    /// Lists the attachments of an email without downloading their contents.
+    pub async fn get_email_attachments(
+        &self,
+        email_id: &str,
+    ) -> Result<Vec<Attachment>, GraphClientError> {
+        let url = format!(
+            "{}/me/messages/{}/attachments?$select=id,name,contentType,size,isInline",
+            GRAPH_API_BASE_URL, email_id
+        );
+        self.fetch_all_items::<Attachment>(&url).await
+    }
+
+    pub async fn get_attachment(
+        &self,
+        email_id: &str,
+        attachment_id: &str,
+    ) -> Result<Attachment, GraphClientError> {
+        let url = format!(
+            "{}/me/messages/{}/attachments/{}?$select=id,name,contentType,size,isInline",
+            GRAPH_API_BASE_URL, email_id, attachment_id
+        );
+        let response = self.send(self.get(&url)).await?;
+
+        if response.status().is_success() {
+            let attachment: Attachment = response.json().await?;
+            Ok(attachment)
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    /// Starts downloading the raw contents of a single attachment, refusing
+    /// attachments larger than `max_size` bytes before any content is read.
+    pub async fn get_attachment_content(
+        &self,
+        email_id: &str,
+        attachment_id: &str,
+        max_size: u64,
+    ) -> Result<AttachmentContent, GraphClientError> {
+        let attachment = self.get_attachment(email_id, attachment_id).await?;
+        self.download_attachment(email_id, attachment, max_size)
+            .await
+    }
+
+    /// Like `get_attachment_content`, for an attachment whose metadata has
+    /// already been fetched.
+    pub async fn download_attachment(
+        &self,
+        email_id: &str,
+        attachment: Attachment,
+        max_size: u64,
+    ) -> Result<AttachmentContent, GraphClientError> {
+        if attachment.size > max_size {
+            return Err(GraphClientError::AttachmentTooLarge(attachment.size));
+        }
+
+        let url = format!(
+            "{}/me/messages/{}/attachments/{}/$value",
+            GRAPH_API_BASE_URL, email_id, attachment.id
+        );
+        let response = self.send_streaming(self.get(&url)).await?;
+
+        if response.status().is_success() {
+            if let Some(size) = response.content_length().filter(|size| *size > max_size) {
+                return Err(GraphClientError::AttachmentTooLarge(size));
+            }
+
+            let content_type = attachment
+                .content_type
+                .unwrap_or_else(|| "application/octet-stream".to_string());
+            Ok(AttachmentContent {
+                name: attachment.name,
+                content_type,
+                response,
+            })
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    pub async fn move_email_to_folder(
+        &self,
+        email_id: &str,
+        folder_id: &str,
+    ) -> Result<Email, GraphClientError> {
+        let url = format!("{}/me/messages/{}/move", GRAPH_API_BASE_URL, email_id);
+        let payload = json!({ "destinationId": folder_id });
+
+        let response = self.send(self.post(&url).json(&payload)).await?;
+
+        if response.status().is_success() {
+            let email: Email = response.json().await?;
+            Ok(email)
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    pub async fn update_email(
+        &self,
+        email_id: &str,
+        update: &EmailUpdate,
+    ) -> Result<Email, GraphClientError> {
+        let url = format!("{}/me/messages/{}", GRAPH_API_BASE_URL, email_id);
+        let response = self
+            .send(self.patch(&url).json(&update.to_graph_json()))
+            .await?;
+
+        if response.status().is_success() {
+            let email: Email = response.json().await?;
+            Ok(email)
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    pub async fn list_tags(&self) -> Result<Vec<Tag>, GraphClientError> {
+        let url = format!("{}/me/outlook/masterCategories", GRAPH_API_BASE_URL);
+        self.fetch_all_items::<Tag>(&url).await
+    }
+
+    pub async fn add_tag(&self, email_id: &str, tag: &str) -> Result<Email, GraphClientError> {
+        let email = self.get_email_by_id(email_id).await?;
+        if email.categories.iter().any(|category| category == tag) {
+            return Ok(email);
+        }
+
+        let mut categories = email.categories;
+        categories.push(tag.to_string());
+        self.set_categories(email_id, &categories).await
+    }
+
+    pub async fn remove_tag(&self, email_id: &str, tag: &str) -> Result<Email, GraphClientError> {
+        let email = self.get_email_by_id(email_id).await?;
+        let categories = email
+            .categories
+            .into_iter()
+            .filter(|category| category != tag)
+            .collect::<Vec<String>>();
+        self.set_categories(email_id, &categories).await
+    }
+
+    async fn set_categories(
+        &self,
+        email_id: &str,
+        categories: &[String],
+    ) -> Result<Email, GraphClientError> {
+        let url = format!("{}/me/messages/{}", GRAPH_API_BASE_URL, email_id);
+        let payload = json!({ "categories": categories });
+        let response = self.send(self.patch(&url).json(&payload)).await?;
+
+        if response.status().is_success() {
+            let email: Email = response.json().await?;
+            Ok(email)
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    /// Deletes an email by moving it to the Deleted Items folder, or the
+    /// account's `trash` alias, where it can still be recovered. Returns the
+    /// moved email with the id of the folder it was in.
+    pub async fn delete_email(&mut self, email_id: &str) -> Result<DeletedEmail, GraphClientError> {
+        let url = format!(
+            "{}/me/messages/{}?$select=parentFolderId",
+            GRAPH_API_BASE_URL, email_id
+        );
+        let response = self.send(self.get(&url)).await?;
+        if !response.status().is_success() {
+            return Err(GraphClientError::Request(response.status()));
+        }
+        let previous: EmailFolder = response.json().await?;
+
+        let folder_id = self.get_standard_folder_id("deleteditems").await?;
+        let email = self.move_email_to_folder(email_id, &folder_id).await?;
+        Ok(DeletedEmail {
+            email,
+            previous_folder_id: previous.parent_folder_id,
+        })
+    }
+
+    /// Downloads the full MIME content of an email.
+    pub async fn get_email_mime(&self, email_id: &str) -> Result<Vec<u8>, GraphClientError> {
+        let url = format!("{}/me/messages/{}/$value", GRAPH_API_BASE_URL, email_id);
+        let response = self.send(self.get(&url)).await?;
+
+        if response.status().is_success() {
+            Ok(response.bytes().await?.to_vec())
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    /// Reads the sender authentication verdicts from an email's headers.
+    pub async fn get_auth_results(&self, email_id: &str) -> Result<AuthResults, GraphClientError> {
+        let url = format!(
+            "{}/me/messages/{}?$select=internetMessageHeaders",
+            GRAPH_API_BASE_URL, email_id
+        );
+        let response = self.send(self.get(&url)).await?;
+
+        if response.status().is_success() {
+            let json: Value = response.json().await?;
+            let headers: Vec<MessageHeader> =
+                serde_json::from_value(json["internetMessageHeaders"].clone()).unwrap_or_default();
+            Ok(AuthResults::from_headers(&headers))
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    /// Starts downloading the full MIME content of an email as a stream.
+    pub async fn get_email_mime_stream(
+        &self,
+        email_id: &str,
+    ) -> Result<BoxStream<'static, reqwest::Result<Bytes>>, GraphClientError> {
+        let url = format!("{}/me/messages/{}/$value", GRAPH_API_BASE_URL, email_id);
+        let response = self.send_streaming(self.get(&url)).await?;
+
+        if response.status().is_success() {
+            Ok(response.bytes_stream().boxed())
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    /// Imports a raw RFC 822 message into a folder, streaming it to Graph.
+    ///
+    /// Graph only accepts MIME content for new drafts, so the message is
+    /// parsed into a temporary draft first, then recreated in the folder as a
+    /// received message with the given flags and the dates from its headers.
+    pub async fn import_email<S, E>(
+        &mut self,
+        folder_name: &str,
+        mime: S,
+        flags: ImportFlags,
+    ) -> Result<Email, GraphClientError>
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + Sync + 'static,
+        E: Into<Box<dyn std::error::Error + Send + Sync>> + Send + Sync + 'static,
+    {
+        let folder_id = self.get_folder_id_by_name(folder_name).await?;
+
+        let url = format!("{}/me/messages", GRAPH_API_BASE_URL);
+        let response = self
+            .send(
+                self.post(&url)
+                    .header(reqwest::header::CONTENT_TYPE, "text/plain")
+                    .body(reqwest::Body::wrap_stream(base64_stream(mime))),
+            )
+            .await?;
+        if !response.status().is_success() {
+            return Err(GraphClientError::Request(response.status()));
+        }
+        let draft: EmailId = response.json().await?;
+
+        let result = self
+            .recreate_as_received(&draft.id, &folder_id, flags)
+            .await;
+        if let Err(err) = self.delete_email_permanently(&draft.id).await {
+            error!("Failed to delete import draft {}: {}", draft.id, err);
+        }
+        result
+    }
+
+    /// Copies a draft into a folder as a received, non-draft message. Only a
+    /// message created with `PR_MESSAGE_FLAGS` set is stored as received.
+    async fn recreate_as_received(
+        &self,
+        draft_id: &str,
+        folder_id: &str,
+        flags: ImportFlags,
+    ) -> Result<Email, GraphClientError> {
+        let url = format!(
+            "{}/me/messages/{}?$select={}&$expand=attachments",
+            GRAPH_API_BASE_URL, draft_id, IMPORTED_FIELDS
+        );
+        let response = self.send(self.get(&url)).await?;
+        if !response.status().is_success() {
+            return Err(GraphClientError::Request(response.status()));
+        }
+        let draft: Value = response.json().await?;
+
+        let mut message = serde_json::Map::new();
+        for field in IMPORTED_FIELDS.split(',') {
+            // Graph only accepts custom `X-` headers on new messages.
+            if field == "internetMessageHeaders" {
+                continue;
+            }
+            match draft.get(field) {
+                Some(value) if !value.is_null() => {
+                    message.insert(field.to_string(), value.clone());
+                }
+                _ => {}
+            }
+        }
+        message.insert("isRead".to_string(), json!(flags.is_read));
+        if flags.is_flagged {
+            message.insert("flag".to_string(), json!({ "flagStatus": "flagged" }));
+        }
+
+        let headers: Vec<MessageHeader> =
+            serde_json::from_value(draft["internetMessageHeaders"].clone()).unwrap_or_default();
+        let (sent, received) = message_dates(&headers);
+        let mut properties = vec![json!({
+            "id": "Integer 0x0E07",
+            "value": if flags.is_read { "1" } else { "0" },
+        })];
+        for (id, date) in [("SystemTime 0x0039", sent), ("SystemTime 0x0E06", received)] {
+            if let Some(date) = date {
+                properties.push(json!({
+                    "id": id,
+                    "value": date.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+                }));
+            }
+        }
+        message.insert(
+            "singleValueExtendedProperties".to_string(),
+            Value::Array(properties),
+        );
+
+        let mut attachments = Vec::new();
+        for attachment in draft["attachments"].as_array().into_iter().flatten() {
+            let name = attachment["name"].as_str().unwrap_or_default().to_string();
+            match attachment["@odata.type"].as_str() {
+                Some("#microsoft.graph.fileAttachment") => {
+                    attachments.push(OutgoingAttachment {
+                        name,
+                        content_type: attachment["contentType"].as_str().map(ToString::to_string),
+                        content_bytes: attachment["contentBytes"]
+                            .as_str()
+                            .unwrap_or_default()
+                            .to_string(),
+                        content_id: attachment["isInline"]
+                            .as_bool()
+                            .unwrap_or(false)
+                            .then(|| attachment["contentId"].as_str().map(ToString::to_string))
+                            .flatten(),
+                    });
+                }
+                Some("#microsoft.graph.itemAttachment") => {
+                    let attachment_id = attachment["id"].as_str().unwrap_or_default();
+                    let url = format!(
+                        "{}/me/messages/{}/attachments/{}/$value",
+                        GRAPH_API_BASE_URL, draft_id, attachment_id
+                    );
+                    let response = self.send(self.get(&url)).await?;
+                    if !response.status().is_success() {
+                        return Err(GraphClientError::Request(response.status()));
+                    }
+                    attachments.push(OutgoingAttachment::new(
+                        format!("{}.eml", name),
+                        Some("message/rfc822".to_string()),
+                        &response.bytes().await?,
+                    ));
+                }
+                _ => {}
+            }
+        }
+        let attachments = OutgoingMessage {
+            attachments: Some(attachments),
+            ..Default::default()
+        };
+        let large_attachments = attachments.has_large_attachments();
+        if !large_attachments {
+            message.insert(
+                "attachments".to_string(),
+                attachments.to_graph_json()["attachments"].clone(),
+            );
+        }
+
+        let url = format!(
+            "{}/me/mailFolders/{}/messages",
+            GRAPH_API_BASE_URL, folder_id
+        );
+        let response = self.send(self.post(&url).json(&message)).await?;
+        if !response.status().is_success() {
+            return Err(GraphClientError::Request(response.status()));
+        }
+        let email: Email = response.json().await?;
+        if !large_attachments {
+            return Ok(email);
+        }
+
+        if let Err(err) = self.add_attachments(&email.id, &attachments).await {
+            if let Err(delete_err) = self.delete_email_permanently(&email.id).await {
+                error!(
+                    "Failed to delete incomplete import {}: {}",
+                    email.id, delete_err
+                );
+            }
+            return Err(err);
+        }
+        self.get_email_by_id(&email.id).await
+    }
+
+    /// Applies an update to every message in a folder it would change, such as
+    /// marking the whole folder read or clearing all flags.
+    pub async fn update_folder_emails(
+        &mut self,
+        folder_name: &str,
+        update: &EmailUpdate,
+    ) -> Result<Vec<BatchResult>, GraphClientError> {
+        let Some(filter) = update.pending_filter() else {
+            return Ok(Vec::new());
+        };
+        let folder_id = self.get_folder_id_by_name(folder_name).await?;
+        let url = format!(
+            "{}/me/mailFolders/{}/messages?$filter={}&$select=id&$top=100",
+            GRAPH_API_BASE_URL,
+            folder_id,
+            form_urlencoded::byte_serialize(filter.as_bytes())
+                .collect::<String>()
+                .replace('+', "%20")
+        );
+        let emails = self.fetch_all_items::<EmailId>(&url).await?;
+
+        let body = update.to_graph_json();
+        let requests = emails
+            .into_iter()
+            .map(|email| BatchRequest {
+                url: format!("/me/messages/{}", email.id),
+                email_id: email.id,
+                method: Method::PATCH,
+                body: Some(body.clone()),
+            })
+            .collect();
+        self.batch(requests).await
+    }
+
+    /// Creates a draft that forwards an email as a `message/rfc822`
+    /// attachment, keeping the original headers and attachments verbatim.
+    /// The subject defaults to the original subject prefixed with `Fw:`.
+    pub async fn forward_as_attachment(
+        &self,
+        email_id: &str,
+        mut message: OutgoingMessage,
+    ) -> Result<Email, GraphClientError> {
+        let original = self.get_email_by_id(email_id).await?;
+        let mime = self.get_email_mime(email_id).await?;
+
+        let name = if original.subject.is_empty() {
+            "message.eml".to_string()
+        } else {
+            format!("{}.eml", original.subject.replace(['/', '\\'], "_"))
+        };
+        message
+            .attachments
+            .get_or_insert_with(Vec::new)
+            .push(OutgoingAttachment::new(
+                name,
+                Some("message/rfc822".to_string()),
+                &mime,
+            ));
+        message
+            .subject
+            .get_or_insert_with(|| format!("Fw: {}", original.subject));
+
+        self.create_draft(&message).await
+    }
+
+    /// Sends a message right away. Graph saves a copy to Sent Items. Messages
+    /// with attachments too large for one request are sent through a draft.
+    pub async fn send_mail(&self, message: &OutgoingMessage) -> Result<(), GraphClientError> {
+        if message.has_large_attachments() {
+            let draft = self.create_draft(message).await?;
+            return self.send_draft(&draft.id).await;
+        }
+
+        let url = format!("{}/me/sendMail", GRAPH_API_BASE_URL);
+        let payload = json!({
+            "message": message.to_graph_json(),
+            "saveToSentItems": true,
+        });
+        let response = self.send(self.post(&url).json(&payload)).await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    /// Lists every draft, following all result pages.
+    pub async fn get_drafts(&self) -> Result<Vec<Email>, GraphClientError> {
+        let url = format!(
+            "{}/me/mailFolders/drafts/messages?$top=100",
+            GRAPH_API_BASE_URL
+        );
+        self.fetch_all_items::<Email>(&url).await
+    }
+
+    /// Creates a draft in the Drafts folder. Its id stays the same across
+    /// updates, so it can be used to resume editing.
+    pub async fn create_draft(&self, message: &OutgoingMessage) -> Result<Email, GraphClientError> {
+        let large_attachments = message.has_large_attachments();
+        let payload = if large_attachments {
+            message.to_graph_json_without_attachments()
+        } else {
+            message.to_graph_json()
+        };
+
+        let url = format!("{}/me/messages", GRAPH_API_BASE_URL);
+        let response = self.send(self.post(&url).json(&payload)).await?;
+
+        if !response.status().is_success() {
+            return Err(GraphClientError::Request(response.status()));
+        }
+        let email: Email = response.json().await?;
+        if !large_attachments {
+            return Ok(email);
+        }
+
+        if let Err(err) = self.add_attachments(&email.id, message).await {
+            if let Err(delete_err) = self.delete_draft(&email.id).await {
+                error!(
+                    "Failed to delete incomplete draft {}: {}",
+                    email.id, delete_err
+                );
+            }
+            return Err(err);
+        }
+        self.get_email_by_id(&email.id).await
+    }
+
+    pub async fn update_draft(
+        &self,
+        draft_id: &str,
+        message: &OutgoingMessage,
+    ) -> Result<Email, GraphClientError> {
+        let large_attachments = message.has_large_attachments();
+        let payload = if large_attachments {
+            message.to_graph_json_without_attachments()
+        } else {
+            message.to_graph_json()
+        };
+
+        let url = format!("{}/me/messages/{}", GRAPH_API_BASE_URL, draft_id);
+        let response = self.send(self.patch(&url).json(&payload)).await?;
+
+        if !response.status().is_success() {
+            return Err(GraphClientError::Request(response.status()));
+        }
+        let email: Email = response.json().await?;
+        if !large_attachments {
+            return Ok(email);
+        }
+
+        self.add_attachments(draft_id, message).await?;
+        self.get_email_by_id(draft_id).await
+    }
+
+    /// Adds a message's attachments to a draft one request at a time, so
+    /// their combined size isn't limited by Graph's 4 MB request cap.
+    async fn add_attachments(
+        &self,
+        draft_id: &str,
+        message: &OutgoingMessage,
+    ) -> Result<(), GraphClientError> {
+        for attachment in message.attachments.iter().flatten() {
+            if attachment.size() > INLINE_ATTACHMENTS_LIMIT {
+                self.upload_attachment(draft_id, attachment).await?;
+            } else {
+                let url = format!(
+                    "{}/me/messages/{}/attachments",
+                    GRAPH_API_BASE_URL, draft_id
+                );
+                let response = self
+                    .send(self.post(&url).json(&attachment.to_graph_json()))
+                    .await?;
+                if !response.status().is_success() {
+                    return Err(GraphClientError::Request(response.status()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Uploads an attachment larger than a single request allows to a draft
+    /// through an upload session, in chunks.
+    async fn upload_attachment(
+        &self,
+        draft_id: &str,
+        attachment: &OutgoingAttachment,
+    ) -> Result<(), GraphClientError> {
+        let bytes = base64::decode(&attachment.content_bytes)
+            .map_err(|_| GraphClientError::InvalidAttachment(attachment.name.clone()))?;
+        let size = bytes.len() as u64;
+        if size > UPLOAD_SESSION_LIMIT {
+            return Err(GraphClientError::AttachmentTooLarge(size));
+        }
+
+        let url = format!(
+            "{}/me/messages/{}/attachments/createUploadSession",
+            GRAPH_API_BASE_URL, draft_id
+        );
+        let payload = json!({
+            "AttachmentItem": {
+                "attachmentType": "file",
+                "name": attachment.name,
+                "size": size,
+                "contentType": attachment
+                    .content_type
+                    .as_deref()
+                    .unwrap_or("application/octet-stream"),
+                "isInline": attachment.content_id.is_some(),
+                "contentId": attachment.content_id,
+            }
+        });
+        let response = self.send(self.post(&url).json(&payload)).await?;
+        if !response.status().is_success() {
+            return Err(GraphClientError::Request(response.status()));
+        }
+        let session: UploadSession = response.json().await?;
+
+        // The upload URL is pre-authenticated and rejects requests that also
+        // carry the bearer token.
+        let mut start = 0;
+        for chunk in bytes.chunks(UPLOAD_CHUNK_SIZE) {
+            let end = start + chunk.len() - 1;
+            let request = self
+                .http
+                .client
+                .put(&session.upload_url)
+                .header(
+                    reqwest::header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, end, size),
+                )
+                .body(chunk.to_vec());
+            let response = self.send(request).await?;
+            if !response.status().is_success() {
+                return Err(GraphClientError::Request(response.status()));
+            }
+            start = end + 1;
+        }
+        Ok(())
+    }
 
-            Ok(emails?)
+    pub async fn delete_draft(&self, draft_id: &str) -> Result<(), GraphClientError> {
+        let url = format!("{}/me/messages/{}", GRAPH_API_BASE_URL, draft_id);
+        let response = self.send(self.delete(&url)).await?;
+
+        if response.status().is_success() {
+            Ok(())
         } else {
             Err(GraphClientError::Request(response.status()))
         }
     }
 
-    pub async fn get_user_emails_from_folder_by_name(
-        &mut self,
-        folder_name: &str,
-    ) -> Result<Vec<Email>, GraphClientError> {
-        let folder_id = self.get_folder_id_by_name(folder_name).await?;
-        self.get_user_emails_from_folder(&folder_id).await
-    }
-
-    pub async fn get_email_by_id(&self, email_id: &str) -> Result<Email, GraphClientError> {
-        let url = format!("{}/me/messages/{}", GRAPH_API_BASE_URL, email_id);
-        let response = self
-            .client
-            .get(&url)
-            .bearer_auth(&self.access_token)
-            .send()
-            .await?;
+    /// Sends a draft. Graph saves the sent message to Sent Items.
+    pub async fn send_draft(&self, draft_id: &str) -> Result<(), GraphClientError> {
+        let url = format!("{}/me/messages/{}/send", GRAPH_API_BASE_URL, draft_id);
+        let response = self.send(self.post(&url)).await?;
 
         if response.status().is_success() {
-            let email: Email = response.json().await?;
-            Ok(email)
+            Ok(())
         } else {
             Err(GraphClientError::Request(response.status()))
         }
     }
 
-    pub async fn move_email_to_folder(
-        &self,
-        email_id: &str,
-        folder_id: &str,
-    ) -> Result<Email, GraphClientError> {
-        let url = format!("{}/me/messages/{}/move", GRAPH_API_BASE_URL, email_id);
-        let payload = json!({ "destinationId": folder_id });
-
-        let response = self
-            .client
-            .post(&url)
-            .bearer_auth(&self.access_token)
-            .json(&payload)
-            .send()
-            .await?;
+    /// Permanently deletes an email, bypassing the Deleted Items folder.
+    pub async fn delete_email_permanently(&self, email_id: &str) -> Result<(), GraphClientError> {
+        let url = format!(
+            "{}/me/messages/{}/permanentDelete",
+            GRAPH_API_BASE_URL, email_id
+        );
+        let response = self.send(self.post(&url)).await?;
 
         if response.status().is_success() {
-            let email: Email = response.json().await?;
-            Ok(email)
+            Ok(())
         } else {
             Err(GraphClientError::Request(response.status()))
         }
@@ -248,31 +2578,127 @@ impl GraphClient {
         self.move_email_to_folder(email_id, &folder_id).await
     }
 
+    /// Moves several emails at once, reporting the outcome for each email
+    /// instead of failing the whole operation on the first error.
     pub async fn move_emails_to_folder_by_name(
         &mut self,
         email_ids: Vec<String>,
         folder_name: &str,
-    ) -> Result<Vec<Email>, GraphClientError> {
-        let mut moved_emails = Vec::new();
+    ) -> Result<Vec<BatchResult>, GraphClientError> {
+        let folder_id = self.get_folder_id_by_name(folder_name).await?;
+        self.move_emails_to_folder(email_ids, &folder_id).await
+    }
+
+    async fn move_emails_to_folder(
+        &self,
+        email_ids: Vec<String>,
+        folder_id: &str,
+    ) -> Result<Vec<BatchResult>, GraphClientError> {
+        let requests = email_ids
+            .into_iter()
+            .map(|email_id| BatchRequest {
+                url: format!("/me/messages/{}/move", email_id),
+                email_id,
+                method: Method::POST,
+                body: Some(json!({ "destinationId": folder_id })),
+            })
+            .collect();
+        self.batch(requests).await
+    }
+
+    /// Finds messages in a folder that share a `Message-Id` with an earlier
+    /// one and, unless `dry_run` is set, moves them to Deleted Items.
+    pub async fn dedupe_folder(
+        &mut self,
+        folder_name: &str,
+        dry_run: bool,
+    ) -> Result<DedupeReport, GraphClientError> {
+        let folder_id = self.get_folder_id_by_name(folder_name).await?;
+        let url = format!(
+            "{}/me/mailFolders/{}/messages?$select=id,internetMessageId,receivedDateTime&$top=100",
+            GRAPH_API_BASE_URL, folder_id
+        );
+        let messages = self.fetch_all_items::<MessageIdentity>(&url).await?;
+        let duplicates = find_duplicates(messages);
+
+        let results = if dry_run || duplicates.is_empty() {
+            Vec::new()
+        } else {
+            let ids = duplicates.iter().map(|d| d.id.clone()).collect();
+            let trash_id = self.get_standard_folder_id("deleteditems").await?;
+            self.move_emails_to_folder(ids, &trash_id).await?
+        };
+
+        Ok(DedupeReport {
+            duplicates,
+            results,
+        })
+    }
+
+    /// Exports a folder as an mboxrd stream, oldest message first. Message ids
+    /// are listed up front, then each message is downloaded only as the
+    /// stream is consumed.
+    pub async fn export_folder_mbox(
+        mut self,
+        folder_name: &str,
+    ) -> Result<BoxStream<'static, Result<Bytes, GraphClientError>>, GraphClientError> {
+        let folder_id = self.get_folder_id_by_name(folder_name).await?;
+        let url = format!(
+            "{}/me/mailFolders/{}/messages?$select=id,internetMessageId,receivedDateTime&$orderby=receivedDateTime&$top=100",
+            GRAPH_API_BASE_URL, folder_id
+        );
+        let messages = self.fetch_all_items::<MessageIdentity>(&url).await?;
+
+        let client = Arc::new(self);
+        Ok(stream::iter(messages)
+            .then(move |message| {
+                let client = client.clone();
+                async move {
+                    let mime = client.get_email_mime(&message.id).await?;
+                    Ok::<_, GraphClientError>(Bytes::from(mbox_entry(
+                        &mime,
+                        &message.received_date_time,
+                    )))
+                }
+            })
+            .boxed())
+    }
+
+    pub async fn get_automatic_replies(&self) -> Result<AutomaticReplies, GraphClientError> {
+        let url = format!(
+            "{}/me/mailboxSettings/automaticRepliesSetting",
+            GRAPH_API_BASE_URL
+        );
+        let response = self.send(self.get(&url)).await?;
 
-        for email_id in email_ids {
-            let moved_email = self
-                .move_email_to_folder_by_name(&email_id, folder_name)
-                .await?;
-            moved_emails.push(moved_email);
+        if response.status().is_success() {
+            let settings: AutomaticReplies = response.json().await?;
+            Ok(settings)
+        } else {
+            Err(GraphClientError::Request(response.status()))
         }
+    }
 
-        Ok(moved_emails)
+    pub async fn set_automatic_replies(
+        &self,
+        settings: &AutomaticReplies,
+    ) -> Result<AutomaticReplies, GraphClientError> {
+        let url = format!("{}/me/mailboxSettings", GRAPH_API_BASE_URL);
+        let payload = json!({ "automaticRepliesSetting": settings });
+        let response = self.send(self.patch(&url).json(&payload)).await?;
+
+        if response.status().is_success() {
+            let json: Value = response.json().await?;
+            let settings = serde_json::from_value(json["automaticRepliesSetting"].clone())?;
+            Ok(settings)
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
     }
 
     pub async fn get_user_profile(&self) -> Result<Profile, GraphClientError> {
         let url = format!("{}/me", GRAPH_API_BASE_URL);
-        let response = self
-            .client
-            .get(&url)
-            .bearer_auth(&self.access_token)
-            .send()
-            .await?;
+        let response = self.send(self.get(&url)).await?;
 
         if response.status().is_success() {
             let json: Profile = response.json().await?;
@@ -282,6 +2708,58 @@ impl GraphClient {
         }
     }
 
+    /// Sends requests through the Graph JSON batching endpoint, in chunks of
+    /// at most `GRAPH_BATCH_LIMIT` requests, returning one result per request
+    /// in the original order.
+    async fn batch(
+        &self,
+        requests: Vec<BatchRequest>,
+    ) -> Result<Vec<BatchResult>, GraphClientError> {
+        let url = format!("{}/$batch", GRAPH_API_BASE_URL);
+        let mut results = Vec::with_capacity(requests.len());
+
+        for chunk in requests.chunks(GRAPH_BATCH_LIMIT) {
+            let batch_requests = chunk
+                .iter()
+                .enumerate()
+                .map(|(index, request)| {
+                    let mut value = json!({
+                        "id": index.to_string(),
+                        "method": request.method.as_str(),
+                        "url": request.url,
+                        "headers": { "Prefer": PREFER_IMMUTABLE_ID },
+                    });
+                    if let Some(body) = &request.body {
+                        value["body"] = body.clone();
+                        value["headers"]["Content-Type"] = json!("application/json");
+                    }
+                    value
+                })
+                .collect::<Vec<Value>>();
+            let payload = json!({ "requests": batch_requests });
+
+            let response = self.send(self.post(&url).json(&payload)).await?;
+            if !response.status().is_success() {
+                return Err(GraphClientError::Request(response.status()));
+            }
+
+            let json: Value = response.json().await?;
+            let responses = json["responses"]
+                .as_array()
+                .ok_or_else(|| GraphClientError::Parse("batch responses", json.clone()))?;
+
+            for (index, request) in chunk.iter().enumerate() {
+                let index = index.to_string();
+                let response = responses
+                    .iter()
+                    .find(|response| response["id"].as_str() == Some(index.as_str()));
+                results.push(BatchResult::new(&request.email_id, response));
+            }
+        }
+
+        Ok(results)
+    }
+
     async fn fetch_all_items<T: DeserializeOwned>(
         &self,
         base_url: &str,
@@ -290,12 +2768,7 @@ impl GraphClient {
         let mut next_link: Option<String> = Some(base_url.to_string());
 
         while let Some(url) = next_link {
-            let response = self
-                .client
-                .get(&url)
-                .bearer_auth(&self.access_token)
-                .send()
-                .await?;
+            let response = self.send(self.get(&url)).await?;
 
             if response.status().is_success() {
                 let json: Value = response.json().await?;
@@ -339,12 +2812,7 @@ impl GraphClient {
                 break;
             }
 
-            let response = self
-                .client
-                .get(&url)
-                .bearer_auth(&self.access_token)
-                .send()
-                .await?;
+            let response = self.send(self.get(&url)).await?;
 
             if response.status().is_success() {
                 let json: Value = response.json().await?;
@@ -372,27 +2840,121 @@ impl GraphClient {
         Ok((items, has_more_pages))
     }
 
+    async fn get_child_folders(
+        &self,
+        parent_id: Option<&str>,
+    ) -> Result<Vec<Folder>, GraphClientError> {
+        let url = match parent_id {
+            Some(parent_id) => format!(
+                "{}/me/mailFolders/{}/childFolders",
+                GRAPH_API_BASE_URL, parent_id
+            ),
+            None => format!("{}/me/mailFolders", GRAPH_API_BASE_URL),
+        };
+        self.fetch_all_items::<Folder>(&url).await
+    }
+
+    /// Fetches a folder by id or well-known name, or `None` if the mailbox
+    /// doesn't have it.
+    async fn get_folder(&self, folder_id: &str) -> Result<Option<Folder>, GraphClientError> {
+        let url = format!("{}/me/mailFolders/{}", GRAPH_API_BASE_URL, folder_id);
+        let response = self.send(self.get(&url)).await?;
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            Ok(None)
+        } else if response.status().is_success() {
+            Ok(Some(response.json().await?))
+        } else {
+            Err(GraphClientError::Request(response.status()))
+        }
+    }
+
+    async fn find_child_folder(
+        &self,
+        parent_id: &str,
+        name: &str,
+    ) -> Result<Option<Folder>, GraphClientError> {
+        Ok(self
+            .get_child_folders(Some(parent_id))
+            .await?
+            .into_iter()
+            .find(|f| f.display_name.to_lowercase() == name.to_lowercase()))
+    }
+
+    /// Finds a top-level folder by display name, falling back to the Graph
+    /// well-known folder for standard names such as `Trash`.
+    async fn find_root_folder(&self, name: &str) -> Result<Option<Folder>, GraphClientError> {
+        let folder = self
+            .get_child_folders(None)
+            .await?
+            .into_iter()
+            .find(|f| f.display_name.to_lowercase() == name.to_lowercase());
+
+        match (folder, well_known_folder_name(name)) {
+            (Some(folder), _) => Ok(Some(folder)),
+            (None, Some(well_known_name)) => self.get_folder(well_known_name).await,
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Finds a folder by its `/`-separated path, e.g. `Projects/2023`, matching
+    /// each level's display name case-insensitively.
+    async fn find_folder_by_path(&self, path: &str) -> Result<Option<Folder>, GraphClientError> {
+        let mut folder: Option<Folder> = None;
+
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            folder = match &folder {
+                Some(parent) => self.find_child_folder(&parent.id, name).await?,
+                None => self.find_root_folder(name).await?,
+            };
+            if folder.is_none() {
+                return Ok(None);
+            }
+        }
+
+        Ok(folder)
+    }
+
+    /// Resolves a folder path, which may start with one of the account's
+    /// aliases, to a folder id.
     async fn get_folder_id_by_name(
         &mut self,
         folder_name: &str,
     ) -> Result<String, GraphClientError> {
-        if let Some(folder_id) = self.folder_cache.get(folder_name) {
+        let path = self.resolve_folder_path(folder_name);
+
+        if let Some(folder_id) = self.folder_cache.get(&path) {
             return Ok(folder_id.to_string());
         }
 
-        let folders = self.get_user_folders().await?;
-        if let Some(folder) = folders
-            .into_iter()
-            .find(|f| f.display_name.to_lowercase() == folder_name.to_lowercase())
-        {
+        if let Some(folder) = self.find_folder_by_path(&path).await? {
             let folder_id = folder.id;
-            self.folder_cache
-                .insert(folder_name.to_string(), folder_id.clone());
+            self.folder_cache.insert(path, folder_id.clone());
             Ok(folder_id)
         } else {
             Err(GraphClientError::FolderNotFound(folder_name.to_string()))
         }
     }
+
+    /// Resolves one of the standard folders the API moves messages to on its
+    /// own, given its well-known name. An account alias for the folder, e.g.
+    /// `trash` for `deleteditems`, takes precedence.
+    pub async fn get_standard_folder_id(
+        &mut self,
+        well_known_name: &str,
+    ) -> Result<String, GraphClientError> {
+        let alias = self
+            .folder_aliases
+            .keys()
+            .filter(|alias| well_known_folder_name(alias) == Some(well_known_name))
+            .min()
+            .cloned();
+
+        match alias {
+            Some(alias) => self.get_folder_id_by_name(&alias).await,
+            None => Ok(well_known_name.to_string()),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -401,6 +2963,14 @@ mod tests {
 
     use super::*;
 
+    /// A received message from the fixtures, with the given id.
+    fn fixture_email(id: &str) -> Email {
+        let json = fs::read_to_string("src/fixtures/empty-subject.json").unwrap();
+        let mut email: Email = serde_json::from_str(&json).unwrap();
+        email.id = id.to_string();
+        email
+    }
+
     #[test]
     fn test_body() {
         let body = r#"
@@ -458,4 +3028,519 @@ mod tests {
         assert!(email.sender.is_none());
         assert!(email.from.is_none());
     }
+
+    #[test]
+    fn test_group_into_threads() {
+        let root = fixture_email("root");
+
+        let mut index = base64::decode(&root.conversation_index).unwrap();
+        index.extend_from_slice(&[0, 0, 0, 0, 1]);
+        let mut reply = fixture_email("reply");
+        reply.conversation_index = base64::encode(&index);
+
+        let mut other = fixture_email("other");
+        other.conversation_id = "other-conversation".to_string();
+
+        let threads = group_into_threads(vec![reply, other, root]);
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads[0].messages.len(), 1);
+        assert_eq!(threads[0].messages[0].replies.len(), 1);
+        assert_eq!(threads[0].messages[0].replies[0].email.id, "reply");
+        assert_eq!(threads[1].messages[0].email.id, "other");
+    }
+
+    #[test]
+    fn test_conversation() {
+        let email = |id: &str, received: &str, is_read: bool| {
+            let mut email = fixture_email(id);
+            email.received_date_time = received.to_string();
+            email.is_read = is_read;
+            email
+        };
+
+        let conversation = Conversation::new(
+            "conversation".to_string(),
+            vec![
+                email("latest", "2023-03-25T03:00:00Z", true),
+                email("first", "2023-03-25T01:00:00Z", true),
+                email("unread", "2023-03-25T02:00:00Z", false),
+            ],
+        );
+        let view: Vec<(&str, bool)> = conversation
+            .messages
+            .iter()
+            .map(|message| (message.email.id.as_str(), message.expanded))
+            .collect();
+        assert_eq!(
+            view,
+            vec![("first", false), ("unread", true), ("latest", true)]
+        );
+    }
+
+    #[test]
+    fn test_sort_criterion() {
+        let sort: SortCriterion = "date:desc".parse().unwrap();
+        assert_eq!(sort.to_order_by(), "receivedDateTime desc");
+
+        let sort: SortCriterion = "From".parse().unwrap();
+        assert_eq!(sort.to_order_by(), "from/emailAddress/name asc");
+
+        assert!("size".parse::<SortCriterion>().is_err());
+        assert!("date:sideways".parse::<SortCriterion>().is_err());
+    }
+
+    #[test]
+    fn test_batch_result() {
+        let email = serde_json::to_value(fixture_email("a")).unwrap();
+
+        let ok = json!({ "id": "0", "status": 201, "body": email });
+        let result = BatchResult::new("a", Some(&ok));
+        assert!(result.error.is_none());
+        assert_eq!(result.email.unwrap().subject, "");
+
+        let failed = json!({
+            "id": "1",
+            "status": 404,
+            "body": { "error": { "code": "ErrorItemNotFound", "message": "Not found." } }
+        });
+        let result = BatchResult::new("b", Some(&failed));
+        assert!(result.email.is_none());
+        assert_eq!(result.status, 404);
+        assert_eq!(result.error.as_deref(), Some("Not found."));
+
+        assert!(BatchResult::new("c", None).error.is_some());
+    }
+
+    #[test]
+    fn test_expand_folder_alias() {
+        let aliases = HashMap::from([
+            ("sent".to_string(), "[Gmail]/Sent Mail".to_string()),
+            ("trash".to_string(), "[Gmail]/Bin/".to_string()),
+        ]);
+        assert_eq!(
+            expand_folder_alias(&aliases, "Sent").as_deref(),
+            Some("[Gmail]/Sent Mail")
+        );
+        assert_eq!(
+            expand_folder_alias(&aliases, "trash/2023").as_deref(),
+            Some("[Gmail]/Bin/2023")
+        );
+        assert_eq!(expand_folder_alias(&aliases, "Inbox/Sent"), None);
+        assert_eq!(expand_folder_alias(&aliases, "Projects"), None);
+    }
+
+    #[test]
+    fn test_well_known_folder_name() {
+        assert_eq!(well_known_folder_name("Inbox"), Some("inbox"));
+        assert_eq!(well_known_folder_name("Junk Email"), Some("junkemail"));
+        assert_eq!(well_known_folder_name("trash"), Some("deleteditems"));
+        assert_eq!(well_known_folder_name("Projects"), None);
+    }
+
+    #[test]
+    fn test_outgoing_message_to_graph_json() {
+        let message = OutgoingMessage {
+            subject: Some("Hello".to_string()),
+            to: Some(vec!["a@example.com".to_string()]),
+            text: Some("plain".to_string()),
+            html: Some("<p>rich</p>".to_string()),
+            read_receipt: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            message.to_graph_json(),
+            json!({
+                "subject": "Hello",
+                "toRecipients": [{ "emailAddress": { "address": "a@example.com" } }],
+                "body": { "contentType": "html", "content": "<p>rich</p>" },
+                "isReadReceiptRequested": true,
+            })
+        );
+
+        let update = OutgoingMessage {
+            text: Some("plain".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            update.to_graph_json(),
+            json!({ "body": { "contentType": "text", "content": "plain" } })
+        );
+
+        let with_logo = OutgoingMessage {
+            attachments: Some(vec![OutgoingAttachment {
+                name: "logo.png".to_string(),
+                content_type: Some("image/png".to_string()),
+                content_bytes: base64::encode(b"png"),
+                content_id: Some("logo".to_string()),
+            }]),
+            ..Default::default()
+        };
+        assert_eq!(
+            with_logo.to_graph_json()["attachments"][0],
+            json!({
+                "@odata.type": "#microsoft.graph.fileAttachment",
+                "name": "logo.png",
+                "contentType": "image/png",
+                "contentBytes": "cG5n",
+                "isInline": true,
+                "contentId": "logo",
+            })
+        );
+    }
+
+    #[test]
+    fn test_outgoing_message_deserialize() {
+        let message: OutgoingMessage = serde_json::from_value(json!({
+            "subject": "Hello",
+            "to": ["a@example.com"],
+            "readReceipt": true,
+            "attachments": [{
+                "name": "logo.png",
+                "contentType": "image/png",
+                "contentBytes": "cG5n",
+                "contentId": "logo",
+            }],
+        }))
+        .unwrap();
+        assert_eq!(message.subject.as_deref(), Some("Hello"));
+        assert_eq!(message.read_receipt, Some(true));
+        let attachment = &message.attachments.unwrap()[0];
+        assert_eq!(attachment.content_type.as_deref(), Some("image/png"));
+        assert_eq!(attachment.content_id.as_deref(), Some("logo"));
+    }
+
+    #[test]
+    fn test_large_attachments() {
+        let attachment =
+            |len: usize| OutgoingAttachment::new("a.bin".to_string(), None, &vec![0; len]);
+        assert_eq!(attachment(4).size(), 4);
+        assert_eq!(attachment(5).size(), 5);
+
+        let small = OutgoingMessage {
+            attachments: Some(vec![attachment(2 * 1024 * 1024)]),
+            ..Default::default()
+        };
+        assert!(!small.has_large_attachments());
+
+        let large = OutgoingMessage {
+            attachments: Some(vec![
+                attachment(2 * 1024 * 1024),
+                attachment(2 * 1024 * 1024),
+            ]),
+            ..Default::default()
+        };
+        assert!(large.has_large_attachments());
+        assert!(large.to_graph_json_without_attachments()["attachments"].is_null());
+    }
+
+    #[test]
+    fn test_email_update() {
+        let email = fixture_email("a");
+        assert_eq!(email.flag.flag_status, FlagStatus::NotFlagged);
+
+        let update = EmailUpdate {
+            is_read: Some(true),
+            flag_status: Some(FlagStatus::Flagged),
+        };
+        assert_eq!(
+            update.to_graph_json(),
+            json!({ "isRead": true, "flag": { "flagStatus": "flagged" } })
+        );
+        assert_eq!(EmailUpdate::default().to_graph_json(), json!({}));
+    }
+
+    #[test]
+    fn test_list_options_query_params() {
+        let options = ListOptions {
+            limit: Some(25),
+            unread: Some(true),
+            flagged: Some(true),
+            sort: Some("date:desc".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.query_params(),
+            vec![
+                ("$top", "25".to_string()),
+                (
+                    "$filter",
+                    "receivedDateTime ge 1900-01-01T00:00:00Z and isRead eq false and flag/flagStatus eq 'flagged'".to_string()
+                ),
+                ("$orderby", "receivedDateTime desc".to_string()),
+                ("$count", "true".to_string()),
+            ]
+        );
+        assert!(options.validate().is_ok());
+
+        let options = ListOptions {
+            unread: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.query_params(),
+            vec![
+                ("$filter", "isRead eq true".to_string()),
+                ("$count", "true".to_string()),
+            ]
+        );
+
+        let options = ListOptions {
+            flagged: Some(true),
+            sort: Some("subject".parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+
+        let options = ListOptions {
+            search: Some("from:alice".to_string()),
+            unread: Some(true),
+            ..Default::default()
+        };
+        assert!(options.validate().is_err());
+
+        let options = ListOptions {
+            search: Some("from:alice".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            options.query_params(),
+            vec![("$search", "\"from:alice\"".to_string())]
+        );
+        assert!(options.validate().is_ok());
+    }
+
+    #[test]
+    fn test_cursor() {
+        let next_link = format!("{}/me/messages?$skip=10", GRAPH_API_BASE_URL);
+        let cursor = encode_cursor(&next_link);
+        assert_eq!(decode_cursor(&cursor).unwrap(), next_link);
+
+        let evil = encode_cursor("https://example.com/steal");
+        assert!(decode_cursor(&evil).is_err());
+        assert!(decode_cursor("not base64!").is_err());
+    }
+
+    #[test]
+    fn test_email_delta() {
+        let email = serde_json::to_value(fixture_email("a")).unwrap();
+        let delta_link = format!(
+            "{}/me/mailFolders/inbox/messages/delta?$deltatoken=abc",
+            GRAPH_API_BASE_URL
+        );
+        let json = serde_json::json!({
+            "value": [
+                email,
+                { "id": "gone", "@removed": { "reason": "deleted" } }
+            ],
+            "@odata.deltaLink": delta_link,
+        });
+
+        let delta = EmailDelta::from_graph_json(&json).unwrap();
+        assert_eq!(delta.emails.len(), 1);
+        assert_eq!(delta.removed, vec!["gone".to_string()]);
+        assert_eq!(delta.next_cursor, None);
+        assert_eq!(
+            decode_cursor(delta.delta_cursor.as_deref().unwrap()).unwrap(),
+            delta_link
+        );
+    }
+
+    #[test]
+    fn test_folder_delta() {
+        let json = serde_json::json!({
+            "value": [
+                {
+                    "id": "new",
+                    "displayName": "Receipts",
+                    "parentFolderId": "inbox-id",
+                    "childFolderCount": 0,
+                    "unreadItemCount": 2,
+                    "totalItemCount": 5
+                },
+                { "id": "gone", "@removed": { "reason": "deleted" } }
+            ],
+            "@odata.nextLink": format!("{}/me/mailFolders/delta?$skiptoken=abc", GRAPH_API_BASE_URL),
+        });
+
+        let delta = FolderDelta::from_graph_json(&json).unwrap();
+        assert_eq!(delta.folders.len(), 1);
+        assert_eq!(delta.folders[0].display_name, "Receipts");
+        assert_eq!(delta.removed, vec!["gone".to_string()]);
+        assert!(delta.next_cursor.is_some());
+        assert_eq!(delta.delta_cursor, None);
+    }
+
+    #[test]
+    fn test_subscription_expiration() {
+        let now = Utc::now();
+        assert_eq!(
+            subscription_expiration(now, None),
+            now + chrono::Duration::minutes(MAX_SUBSCRIPTION_MINUTES)
+        );
+        assert_eq!(
+            subscription_expiration(now, Some(60)),
+            now + chrono::Duration::minutes(60)
+        );
+        assert_eq!(
+            subscription_expiration(now, Some(1)),
+            now + chrono::Duration::minutes(MIN_SUBSCRIPTION_MINUTES)
+        );
+        assert_eq!(
+            subscription_expiration(now, Some(100_000)),
+            now + chrono::Duration::minutes(MAX_SUBSCRIPTION_MINUTES)
+        );
+    }
+
+    #[test]
+    fn test_find_duplicates() {
+        let message = |id: &str, message_id: Option<&str>, received: &str| MessageIdentity {
+            id: id.to_string(),
+            internet_message_id: message_id.map(ToString::to_string),
+            received_date_time: received.to_string(),
+        };
+        let messages = vec![
+            message("b", Some("<1@x>"), "2023-03-25T02:00:00Z"),
+            message("a", Some("<1@x>"), "2023-03-25T01:00:00Z"),
+            message("c", Some("<2@x>"), "2023-03-25T01:00:00Z"),
+            message("d", None, "2023-03-25T01:00:00Z"),
+            message("e", None, "2023-03-25T01:00:00Z"),
+            message("f", Some("<1@x>"), "2023-03-25T03:00:00Z"),
+        ];
+
+        assert_eq!(
+            find_duplicates(messages),
+            vec![
+                Duplicate {
+                    id: "b".to_string(),
+                    internet_message_id: "<1@x>".to_string(),
+                    original_id: "a".to_string(),
+                },
+                Duplicate {
+                    id: "f".to_string(),
+                    internet_message_id: "<1@x>".to_string(),
+                    original_id: "a".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mbox_entry() {
+        let mime = b"Subject: Hi\r\n\r\nFrom here\r\n>From there\r\nFromage\r\n";
+        assert_eq!(
+            String::from_utf8(mbox_entry(mime, "2023-03-25T01:09:18Z")).unwrap(),
+            "From MAILER-DAEMON Sat Mar 25 01:09:18 2023\n\
+             Subject: Hi\n\n>From here\n>>From there\nFromage\n\n"
+        );
+    }
+
+    #[test]
+    fn test_base64_stream() {
+        let chunks = ["He", "llo, ", "", "world"]
+            .map(|chunk| Ok::<_, std::convert::Infallible>(Bytes::from(chunk)));
+        let encoded = futures::executor::block_on(
+            base64_stream(stream::iter(chunks))
+                .map(|chunk| chunk.unwrap().to_vec())
+                .concat(),
+        );
+        assert_eq!(encoded, base64::encode("Hello, world").as_bytes());
+    }
+
+    #[test]
+    fn test_message_dates() {
+        let header = |name: &str, value: &str| MessageHeader {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        let headers = vec![
+            header(
+                "Received",
+                "from mx.example.com by mail.example.com; Sat, 25 Mar 2023 01:10:00 +0000 (UTC)",
+            ),
+            header("Received", "from a by b; Sat, 25 Mar 2023 01:09:30 +0000"),
+            header("Date", "Fri, 24 Mar 2023 22:09:18 -0300"),
+        ];
+        let (sent, received) = message_dates(&headers);
+        assert_eq!(sent.unwrap().to_rfc3339(), "2023-03-25T01:09:18+00:00");
+        assert_eq!(received.unwrap().to_rfc3339(), "2023-03-25T01:10:00+00:00");
+
+        let (sent, received) = message_dates(&headers[2..]);
+        assert_eq!(sent, received);
+        assert_eq!(message_dates(&[]), (None, None));
+    }
+
+    #[test]
+    fn test_email_update_pending_filter() {
+        let update = EmailUpdate {
+            is_read: Some(true),
+            flag_status: Some(FlagStatus::NotFlagged),
+        };
+        assert_eq!(
+            update.pending_filter().as_deref(),
+            Some("isRead eq false or flag/flagStatus ne 'notFlagged'")
+        );
+        assert_eq!(EmailUpdate::default().pending_filter(), None);
+    }
+
+    #[test]
+    fn test_auth_results() {
+        let header = |name: &str, value: &str| MessageHeader {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        let headers = vec![
+            header(
+                "Authentication-Results",
+                "spf=pass (sender IP is 192.0.2.1) smtp.mailfrom=example.com; \
+                 dkim=fail (body hash did not verify) header.d=example.com;dmarc=bestguesspass \
+                 action=none header.from=example.com;compauth=pass reason=109",
+            ),
+            header("Authentication-Results", "spf=fail"),
+        ];
+        assert_eq!(
+            AuthResults::from_headers(&headers),
+            AuthResults {
+                spf: Some(AuthVerdict::Pass),
+                dkim: Some(AuthVerdict::Fail),
+                dmarc: Some(AuthVerdict::Pass),
+                compauth: Some(AuthVerdict::Pass),
+            }
+        );
+
+        let headers = vec![header("Received-SPF", "SoftFail (protection.outlook.com)")];
+        assert_eq!(
+            AuthResults::from_headers(&headers),
+            AuthResults {
+                spf: Some(AuthVerdict::SoftFail),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_inline_cid_images() {
+        let html = r#"<img src="cid:logo@example.com"><img src="cid:missing">"#;
+        let attachments = vec![InlineAttachment {
+            content_type: Some("image/png".to_string()),
+            content_id: Some("<logo@example.com>".to_string()),
+            content_bytes: Some("iVBORw0KGgo=".to_string()),
+            is_inline: true,
+        }];
+        assert_eq!(
+            inline_cid_images(html, &attachments),
+            r#"<img src="data:image/png;base64,iVBORw0KGgo="><img src="cid:missing">"#
+        );
+
+        let html = r#"<img src="cid:image10"><div style="background: url(cid:image1)">"#;
+        let attachments = vec![InlineAttachment {
+            content_type: Some("image/gif".to_string()),
+            content_id: Some("image1".to_string()),
+            content_bytes: Some("R0lG".to_string()),
+            is_inline: true,
+        }];
+        assert_eq!(
+            inline_cid_images(html, &attachments),
+            r#"<img src="cid:image10"><div style="background: url(data:image/gif;base64,R0lG)">"#
+        );
+    }
 }
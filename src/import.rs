@@ -0,0 +1,437 @@
+use std::{
+    convert::Infallible,
+    env,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use futures::stream;
+use postgres_queue::{update_task_data, TaskData, TaskError};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{task::spawn_blocking, time::sleep};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use crate::{
+    auth::refresh_user_token,
+    database::{Database, FolderAlias, User},
+    graph::{GraphClient, GraphClientError, HttpClient, ImportFlags},
+    telemetry::record_job,
+};
+
+/// Name of the queued task that imports an uploaded mbox file.
+pub const IMPORT_MBOX_TASK: &str = "import_mbox";
+
+/// Largest message, in bytes, read from an mbox file. Larger messages are
+/// skipped and counted as failed.
+const MAX_MESSAGE_SIZE: usize = 150 * 1024 * 1024;
+
+/// Times a message is retried while Graph is throttling requests.
+const MAX_RETRIES: u32 = 5;
+
+/// Wait before retrying a throttled request, multiplied by the attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// How far an mbox import has got. It is saved after every message, so an
+/// import that stops can be resumed from the next one.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ImportProgress {
+    /// Bytes of the file read so far.
+    pub offset: u64,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Messages imported so far.
+    pub imported: u64,
+    /// Messages that Graph rejected or that were too large.
+    pub failed: u64,
+}
+
+/// Directory that uploaded mbox files are kept in until they are imported.
+/// The server and the workers must both be able to reach it.
+#[derive(Clone, Debug)]
+pub struct ImportDir(PathBuf);
+
+impl ImportDir {
+    /// Creates the directory if needed. Its path is made absolute, since the
+    /// workers may run from another directory.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        fs::create_dir_all(path)?;
+        Ok(Self(path.canonicalize()?))
+    }
+
+    /// Path for a new upload by the user with id `user_id`.
+    pub fn upload_path(&self, user_id: i32) -> PathBuf {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        self.0.join(format!("{}-{}.mbox", user_id, nanos))
+    }
+}
+
+/// An entry of an mbox file.
+#[derive(Debug, PartialEq)]
+enum MboxEntry {
+    Message(Vec<u8>),
+    TooLarge,
+}
+
+/// Reads the messages of an mboxrd file one at a time, keeping track of
+/// where the next one starts so reading can be resumed from there.
+struct MboxReader<R> {
+    reader: R,
+    line: Vec<u8>,
+    /// Offset of the next line to read.
+    offset: u64,
+    /// Offset of the `From ` line of the next message, once it has been read.
+    next_message: Option<u64>,
+}
+
+impl<R: BufRead> MboxReader<R> {
+    /// Reads from `reader`, which is positioned at `offset` of the file.
+    fn new(reader: R, offset: u64) -> Self {
+        Self {
+            reader,
+            line: Vec::new(),
+            offset,
+            next_message: None,
+        }
+    }
+
+    /// Offset of the next message, where reading can be resumed.
+    fn position(&self) -> u64 {
+        self.next_message.unwrap_or(self.offset)
+    }
+
+    /// Reads a line without its line ending, returning false at the end of
+    /// the file.
+    fn read_line(&mut self) -> io::Result<bool> {
+        self.line.clear();
+        let read = self.reader.read_until(b'\n', &mut self.line)?;
+        self.offset += read as u64;
+        if self.line.ends_with(b"\n") {
+            self.line.pop();
+        }
+        if self.line.ends_with(b"\r") {
+            self.line.pop();
+        }
+        Ok(read > 0)
+    }
+
+    /// Reads the next message, undoing the `>From ` quoting and the blank
+    /// line that ends each entry, and restoring CRLF line endings. Anything
+    /// before the first `From ` line is skipped.
+    fn next_entry(&mut self) -> io::Result<Option<MboxEntry>> {
+        while self.next_message.is_none() {
+            let start = self.offset;
+            if !self.read_line()? {
+                return Ok(None);
+            }
+            if self.line.starts_with(b"From ") {
+                self.next_message = Some(start);
+            }
+        }
+        self.next_message = None;
+
+        let mut message = Vec::new();
+        let mut too_large = false;
+        let mut blank_lines = 0;
+        loop {
+            let start = self.offset;
+            if !self.read_line()? {
+                break;
+            }
+            if self.line.starts_with(b"From ") {
+                self.next_message = Some(start);
+                break;
+            }
+            if self.line.is_empty() {
+                blank_lines += 1;
+                continue;
+            }
+
+            let quotes = self.line.iter().take_while(|&&b| b == b'>').count();
+            let line = if quotes > 0 && self.line[quotes..].starts_with(b"From ") {
+                &self.line[1..]
+            } else {
+                &self.line[..]
+            };
+            too_large |= message.len() + blank_lines * 2 + line.len() + 2 > MAX_MESSAGE_SIZE;
+            if too_large {
+                message = Vec::new();
+            } else {
+                message.extend(b"\r\n".repeat(blank_lines));
+                message.extend_from_slice(line);
+                message.extend_from_slice(b"\r\n");
+            }
+            blank_lines = 0;
+        }
+
+        // Keep any blank lines the message itself ends with, but not the one
+        // that separates entries.
+        if !too_large {
+            message.extend(b"\r\n".repeat(blank_lines.saturating_sub(1)));
+        }
+
+        Ok(Some(if too_large {
+            MboxEntry::TooLarge
+        } else {
+            MboxEntry::Message(message)
+        }))
+    }
+}
+
+/// The header fields of a message, with folded lines joined.
+fn headers(message: &[u8]) -> Vec<(String, String)> {
+    let end = message
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .unwrap_or(message.len());
+    let text = String::from_utf8_lossy(&message[..end]);
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in text.split("\r\n") {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+    }
+    headers
+}
+
+/// Reads a message's read and flagged state from the headers mail programs
+/// store it in: `Status` and `X-Status` as written by mutt and most mbox
+/// tools, Thunderbird's `X-Mozilla-Status`, and Gmail's labels in a Google
+/// Takeout export.
+fn mbox_flags(message: &[u8]) -> ImportFlags {
+    let mut flags = ImportFlags::default();
+    for (name, value) in headers(message) {
+        match name.as_str() {
+            "status" => flags.is_read |= value.contains('R'),
+            "x-status" => flags.is_flagged |= value.contains('F'),
+            "x-mozilla-status" => {
+                let status = u32::from_str_radix(&value, 16).unwrap_or_default();
+                flags.is_read |= status & 0x0001 != 0;
+                flags.is_flagged |= status & 0x0004 != 0;
+            }
+            "x-gmail-labels" => {
+                let labels: Vec<&str> = value.split(',').map(str::trim).collect();
+                flags.is_read |= !labels.contains(&"Unread");
+                flags.is_flagged |= labels.contains(&"Starred");
+            }
+            _ => {}
+        }
+    }
+    flags
+}
+
+pub async fn import_mbox_handler_sync(
+    http: HttpClient,
+    task_id: i32,
+    task_data: TaskData,
+) -> Result<(), TaskError> {
+    let start = Instant::now();
+    let fut = Mutex::new(Box::pin(import_mbox_handler(http, task_id, task_data)));
+    let result = match spawn_blocking(move || {
+        let mut guard = fut.lock().unwrap();
+        futures::executor::block_on(&mut *guard)
+    })
+    .await
+    {
+        Ok(result) => result,
+        Err(e) => Err(TaskError::Custom(e.to_string())),
+    };
+    record_job(IMPORT_MBOX_TASK, start.elapsed(), &result);
+    result
+}
+
+/// Imports the messages of an uploaded mbox file into a folder, one at a
+/// time, from where the saved progress left off. Progress is saved after
+/// every message, and the file is removed once all of it has been read.
+///
+/// Messages Graph can't parse are counted as failed and skipped. Any other
+/// error fails the job with the message it stopped at still to import.
+pub async fn import_mbox_handler(
+    http: HttpClient,
+    task_id: i32,
+    task_data: TaskData,
+) -> Result<(), TaskError> {
+    let field = |name: &str| {
+        task_data[name]
+            .as_str()
+            .map(ToString::to_string)
+            .ok_or_else(|| TaskError::Custom(format!("missing {}", name)))
+    };
+    let user_email = field("user_email")?;
+    let folder = field("folder")?;
+    let path = PathBuf::from(field("path")?);
+    let mut progress: ImportProgress =
+        serde_json::from_value(task_data["progress"].clone()).unwrap_or_default();
+    info!(
+        "Importing {} into {folder} for {user_email} from offset {}",
+        path.display(),
+        progress.offset
+    );
+
+    let database_url = env::var("DATABASE_URL").map_err(|e| TaskError::Custom(e.to_string()))?;
+    let database = Database::new(database_url)
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))?;
+    let client = database
+        .get()
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))?;
+    let user = User::find(&client, &user_email)
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))?
+        .ok_or_else(|| TaskError::Custom(format!("unknown user {}", user_email)))?;
+    let aliases = FolderAlias::list_by_email(&client, &user_email)
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))?;
+
+    let connect = |token: String| {
+        GraphClient::with_http_client(http.clone(), token).with_folder_aliases(
+            aliases
+                .iter()
+                .map(|alias| (alias.alias.clone(), alias.folder.clone())),
+        )
+    };
+    let token = refresh_user_token(&http, &client, &user)
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))?;
+    let mut graph = connect(token);
+
+    let mut file = File::open(&path)?;
+    progress.size = file.metadata()?.len();
+    file.seek(SeekFrom::Start(progress.offset))?;
+    let mut reader = MboxReader::new(BufReader::new(file), progress.offset);
+
+    while let Some(entry) = reader.next_entry()? {
+        let message = match entry {
+            MboxEntry::Message(message) => Bytes::from(message),
+            MboxEntry::TooLarge => {
+                warn!("Skipping message over {MAX_MESSAGE_SIZE} bytes in {folder}");
+                progress.failed += 1;
+                progress.offset = reader.position();
+                update_task_data(&client, task_id, json!({ "progress": progress })).await?;
+                continue;
+            }
+        };
+        let flags = mbox_flags(&message);
+
+        let mut attempts = 0;
+        let mut refreshed = false;
+        let result = loop {
+            let mime = stream::iter([Ok::<_, Infallible>(message.clone())]);
+            match graph.import_email(&folder, mime, flags).await {
+                Err(GraphClientError::Request(StatusCode::UNAUTHORIZED)) if !refreshed => {
+                    // The access token expired during the import.
+                    refreshed = true;
+                    let token = refresh_user_token(&http, &client, &user)
+                        .await
+                        .map_err(|e| TaskError::Custom(e.to_string()))?;
+                    graph = connect(token);
+                }
+                Err(GraphClientError::Request(
+                    StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE,
+                )) if attempts < MAX_RETRIES => {
+                    attempts += 1;
+                    sleep(RETRY_DELAY * attempts).await;
+                }
+                result => break result,
+            }
+        };
+
+        match result {
+            Ok(_) => progress.imported += 1,
+            Err(
+                err @ (GraphClientError::Request(StatusCode::BAD_REQUEST)
+                | GraphClientError::Parse(..)),
+            ) => {
+                warn!("Failed to import message into {folder}: {err}");
+                progress.failed += 1;
+            }
+            Err(err) => return Err(TaskError::Custom(err.to_string())),
+        }
+        progress.offset = reader.position();
+        update_task_data(&client, task_id, json!({ "progress": progress })).await?;
+    }
+
+    fs::remove_file(&path)?;
+    info!(
+        "Imported {} messages into {folder} for {user_email}, {} failed",
+        progress.imported, progress.failed
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(mbox: &[u8], offset: u64) -> (Vec<MboxEntry>, Vec<u64>) {
+        let mut reader = MboxReader::new(&mbox[offset as usize..], offset);
+        let mut entries = Vec::new();
+        let mut positions = Vec::new();
+        while let Some(entry) = reader.next_entry().unwrap() {
+            entries.push(entry);
+            positions.push(reader.position());
+        }
+        (entries, positions)
+    }
+
+    #[test]
+    fn test_mbox_reader() {
+        let mbox = b"From MAILER-DAEMON Sat Mar 25 01:09:18 2023\n\
+            Subject: One\n\n>From here\n>>From there\n\n\n\
+            From MAILER-DAEMON Sun Mar 26 01:09:18 2023\n\
+            Subject: Two\n\nBody\n\n";
+        let first = b"Subject: One\r\n\r\nFrom here\r\n>From there\r\n\r\n";
+        let second = b"Subject: Two\r\n\r\nBody\r\n";
+
+        let (entries, positions) = read_all(mbox, 0);
+        assert_eq!(
+            entries,
+            vec![
+                MboxEntry::Message(first.to_vec()),
+                MboxEntry::Message(second.to_vec())
+            ]
+        );
+        assert_eq!(positions, vec![84, mbox.len() as u64]);
+
+        // Reading resumes at the message after the saved position.
+        let (entries, _) = read_all(mbox, positions[0]);
+        assert_eq!(entries, vec![MboxEntry::Message(second.to_vec())]);
+
+        assert!(read_all(b"", 0).0.is_empty());
+        assert!(read_all(b"not an mbox\n", 0).0.is_empty());
+    }
+
+    #[test]
+    fn test_mbox_flags() {
+        let flags = mbox_flags(b"Subject: Hi\r\nStatus: RO\r\nX-Status: F\r\n\r\nStatus: U\r\n");
+        assert!(flags.is_read && flags.is_flagged);
+
+        let flags = mbox_flags(b"Subject: Hi\r\nStatus: O\r\n\r\n");
+        assert!(!flags.is_read && !flags.is_flagged);
+
+        let flags = mbox_flags(b"X-Mozilla-Status: 0005\r\n\r\n");
+        assert!(flags.is_read && flags.is_flagged);
+
+        let flags = mbox_flags(b"X-Gmail-Labels: Inbox,Unread,\r\n Starred\r\n\r\n");
+        assert!(!flags.is_read && flags.is_flagged);
+
+        let flags = mbox_flags(b"X-Gmail-Labels: Archived\r\n\r\n");
+        assert!(flags.is_read && !flags.is_flagged);
+    }
+}
@@ -1,4 +1,4 @@
-use std::{env, sync::Mutex};
+use std::{env, sync::Mutex, time::Instant};
 
 use base64::{encode_config, URL_SAFE_NO_PAD};
 use meilisearch_sdk::Client;
@@ -10,17 +10,34 @@ use tracing::info;
 
 use crate::{
     database::{Database, User},
-    graph::{Email, GraphClient},
+    graph::{Email, GraphClient, HttpClient},
+    telemetry::record_job,
 };
 
-pub async fn full_index_handler_sync(task_id: i32, task_data: TaskData) -> Result<(), TaskError> {
-    let fut = Mutex::new(Box::pin(full_index_handler(task_id, task_data)));
-    spawn_blocking(move || {
+/// Layout version of the documents in a user's search index. Bump it when
+/// document keys change, as they did when Graph ids became immutable, so the
+/// next full index run clears the documents keyed the old way instead of
+/// indexing every message a second time.
+const INDEX_VERSION: i32 = 1;
+
+pub async fn full_index_handler_sync(
+    http: HttpClient,
+    task_id: i32,
+    task_data: TaskData,
+) -> Result<(), TaskError> {
+    let start = Instant::now();
+    let fut = Mutex::new(Box::pin(full_index_handler(http, task_id, task_data)));
+    let result = match spawn_blocking(move || {
         let mut guard = fut.lock().unwrap();
         futures::executor::block_on(&mut *guard)
     })
     .await
-    .map_err(|e| TaskError::Custom(e.to_string()))?
+    {
+        Ok(result) => result,
+        Err(e) => Err(TaskError::Custom(e.to_string())),
+    };
+    record_job("full_index", start.elapsed(), &result);
+    result
 }
 
 fn generate_deterministic_key(id: &str) -> String {
@@ -30,7 +47,11 @@ fn generate_deterministic_key(id: &str) -> String {
     encode_config(hash, URL_SAFE_NO_PAD)
 }
 
-pub async fn full_index_handler(_task_id: i32, task_data: TaskData) -> Result<(), TaskError> {
+pub async fn full_index_handler(
+    http: HttpClient,
+    _task_id: i32,
+    task_data: TaskData,
+) -> Result<(), TaskError> {
     info!("Full index handler called: {task_data:#?}");
     let user_email = task_data.get("user_email").unwrap().as_str().unwrap();
     let has_pagination = task_data.get("num_pages").is_some();
@@ -45,10 +66,10 @@ pub async fn full_index_handler(_task_id: i32, task_data: TaskData) -> Result<()
 
     let database_url = std::env::var("DATABASE_URL").unwrap();
     let database = Database::new(database_url.clone()).await.unwrap();
-    let client = database.get().await.unwrap();
-    let user = User::find(&client, user_email).await.unwrap().unwrap();
+    let db_client = database.get().await.unwrap();
+    let user = User::find(&db_client, user_email).await.unwrap().unwrap();
 
-    let Some(token) = user.access_token else {
+    let Some(token) = user.access_token.clone() else {
         return Err(TaskError::Custom("No access token".to_string()));
     };
 
@@ -56,7 +77,7 @@ pub async fn full_index_handler(_task_id: i32, task_data: TaskData) -> Result<()
     let master_key = env::var("SEARCH_MASTER_KEY").expect("missing SEARCH_MASTER_KEY");
     info!("Connecting to Meilisearch at {}", endpoint);
     let client = Client::new(endpoint, master_key);
-    let graph = GraphClient::new(token);
+    let graph = GraphClient::with_http_client(http, token);
 
     let (emails, has_more) = if has_pagination {
         graph
@@ -64,7 +85,7 @@ pub async fn full_index_handler(_task_id: i32, task_data: TaskData) -> Result<()
             .await
             .unwrap()
     } else {
-        (graph.get_user_emails().await.unwrap(), false)
+        (graph.get_user_emails(None).await.unwrap(), false)
     };
 
     let documents = emails
@@ -86,9 +107,26 @@ pub async fn full_index_handler(_task_id: i32, task_data: TaskData) -> Result<()
         has_more
     );
 
+    let index = client.index(format!("emails_{}", user.id.unwrap()));
+
+    // Drop documents keyed by an older layout before the first page is added
+    let index_version = user
+        .index_version(&db_client)
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))?;
+    if start_page == 0 && index_version < INDEX_VERSION {
+        info!("Clearing outdated search index for {}", user_email);
+        index
+            .delete_all_documents()
+            .await
+            .map_err(|e| TaskError::Custom(e.to_string()))?;
+        user.set_index_version(&db_client, INDEX_VERSION)
+            .await
+            .map_err(|e| TaskError::Custom(e.to_string()))?;
+    }
+
     // Add emails to Meilisearch
-    let result = client
-        .index(format!("emails_{}", user.id.unwrap()))
+    let result = index
         .add_documents(&documents, Some("uniqueId"))
         .await
         .unwrap();
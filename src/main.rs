@@ -2,19 +2,29 @@ mod api;
 mod auth;
 mod database;
 mod graph;
+mod import;
 mod index;
+mod sanitize;
+mod snooze;
+mod spam;
+mod telemetry;
+mod thumbnail;
 mod token;
 
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use api::Server;
-use clap::{Parser, Subcommand};
+use api::{Limits, Server};
+use clap::{Args, Parser, Subcommand};
 use dotenvy::dotenv;
 use postgres_queue::{initialize_database, TaskRegistry};
 use tracing::info;
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
 use crate::auth::Token;
+use crate::graph::HttpConfig;
+use crate::spam::SpamLearning;
 
 #[derive(Parser, Debug)]
 pub struct Cli {
@@ -34,6 +44,41 @@ enum Command {
 
         #[arg(short, long, env = "DATABASE_URL")]
         database_url: String,
+
+        /// Largest attachment, in bytes, served for download or inlined into a message body
+        #[arg(long, env = "MAX_ATTACHMENT_SIZE", default_value = "26214400")]
+        max_attachment_size: u64,
+
+        /// Largest request body, in bytes, accepted by the API
+        #[arg(long, env = "MAX_REQUEST_SIZE", default_value = "36700160")]
+        max_request_size: usize,
+
+        /// Largest mbox file, in bytes, accepted for import
+        #[arg(long, env = "MAX_IMPORT_SIZE", default_value = "10737418240")]
+        max_import_size: u64,
+
+        /// Directory that uploaded mbox files are kept in until the workers import them
+        #[arg(long, env = "IMPORT_DIR", default_value = "imports")]
+        import_dir: PathBuf,
+
+        /// Requests per minute allowed for each access token, 0 to disable
+        #[arg(long, env = "RATE_LIMIT", default_value = "0")]
+        rate_limit: u32,
+
+        #[command(flatten)]
+        graph: GraphArgs,
+
+        /// Shell command that receives messages reported as spam on stdin
+        #[arg(long, env = "SPAM_LEARN_COMMAND")]
+        spam_learn_command: Option<String>,
+
+        /// Shell command that receives messages reported as not spam on stdin
+        #[arg(long, env = "HAM_LEARN_COMMAND")]
+        ham_learn_command: Option<String>,
+
+        /// Address to serve Prometheus metrics on, if any
+        #[arg(long, env = "METRICS_BIND")]
+        metrics_bind: Option<SocketAddr>,
     },
     Auth {
         #[command(subcommand)]
@@ -45,6 +90,13 @@ enum Command {
 
         #[arg(short, long, env = "DATABASE_URL")]
         database_url: String,
+
+        #[command(flatten)]
+        graph: GraphArgs,
+
+        /// Address to serve Prometheus metrics on, if any
+        #[arg(long, env = "METRICS_BIND")]
+        metrics_bind: Option<SocketAddr>,
     },
     Enqueue {
         #[arg(short, long, env = "DATABASE_URL")]
@@ -61,6 +113,33 @@ enum AuthCommand {
     Set,
 }
 
+/// Network settings for Microsoft Graph traffic, shared by the server and the
+/// workers.
+#[derive(Args, Clone, Debug)]
+struct GraphArgs {
+    /// Seconds to wait for a connection to Microsoft Graph
+    #[arg(long, env = "GRAPH_CONNECT_TIMEOUT", default_value = "10")]
+    graph_connect_timeout: u64,
+
+    /// Seconds to wait for a Microsoft Graph request to complete; streamed downloads are exempt
+    #[arg(long, env = "GRAPH_TIMEOUT", default_value = "60")]
+    graph_timeout: u64,
+
+    /// Proxy URL for Microsoft Graph traffic (http://, https://, socks5:// or socks5h://)
+    #[arg(long, env = "GRAPH_PROXY")]
+    graph_proxy: Option<String>,
+}
+
+impl From<GraphArgs> for HttpConfig {
+    fn from(args: GraphArgs) -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(args.graph_connect_timeout),
+            timeout: Duration::from_secs(args.graph_timeout),
+            proxy: args.graph_proxy,
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv().ok();
@@ -69,7 +148,37 @@ async fn main() -> anyhow::Result<()> {
     setup_logging(&cli)?;
 
     match cli.command {
-        Command::Serve { bind, database_url } => Ok(serve(bind, database_url).await?),
+        Command::Serve {
+            bind,
+            database_url,
+            max_attachment_size,
+            max_request_size,
+            max_import_size,
+            import_dir,
+            rate_limit,
+            graph,
+            spam_learn_command,
+            ham_learn_command,
+            metrics_bind,
+        } => {
+            if let Some(addr) = metrics_bind {
+                info!("Serving metrics on {}", addr);
+                telemetry::install_listener(addr)?;
+            }
+
+            let limits = Limits {
+                max_attachment_size,
+                max_request_size,
+                max_import_size,
+                rate_limit,
+            };
+            let http = HttpConfig::from(graph);
+            let spam = SpamLearning {
+                spam_command: spam_learn_command,
+                ham_command: ham_learn_command,
+            };
+            Ok(serve(bind, database_url, limits, http, spam, import_dir).await?)
+        }
         Command::Auth { command } => match command {
             AuthCommand::Set => auth().await,
             AuthCommand::Get => {
@@ -82,9 +191,18 @@ async fn main() -> anyhow::Result<()> {
         Command::Workers {
             num_workers,
             database_url,
+            graph,
+            metrics_bind,
         } => {
             info!("Starting {} workers...", num_workers);
 
+            if let Some(addr) = metrics_bind {
+                info!("Serving metrics on {}", addr);
+                telemetry::install_listener(addr)?;
+            }
+
+            let http = HttpConfig::from(graph).build_client()?;
+
             let pool = postgres_queue::connect(&database_url)
                 .await
                 .expect("Failed to connect to the database");
@@ -94,7 +212,24 @@ async fn main() -> anyhow::Result<()> {
                 .expect("Failed to initialize database");
 
             let mut registry = TaskRegistry::new();
-            registry.register_task("full_index".to_string(), index::full_index_handler_sync);
+            let index_http = http.clone();
+            registry.register_task("full_index".to_string(), move |task_id, task_data| {
+                index::full_index_handler_sync(index_http.clone(), task_id, task_data)
+            });
+            let unsnooze_http = http.clone();
+            registry.register_task(
+                snooze::UNSNOOZE_TASK.to_string(),
+                move |task_id, task_data| {
+                    snooze::unsnooze_handler_sync(unsnooze_http.clone(), task_id, task_data)
+                },
+            );
+            let import_http = http.clone();
+            registry.register_task(
+                import::IMPORT_MBOX_TASK.to_string(),
+                move |task_id, task_data| {
+                    import::import_mbox_handler_sync(import_http.clone(), task_id, task_data)
+                },
+            );
 
             let tasks = registry
                 .run(&pool, num_workers)
@@ -157,8 +292,17 @@ fn setup_logging(cli: &Cli) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn serve(bind: SocketAddr, database_url: String) -> anyhow::Result<()> {
-    Server::new(bind, database_url).start().await
+async fn serve(
+    bind: SocketAddr,
+    database_url: String,
+    limits: Limits,
+    http: HttpConfig,
+    spam: SpamLearning,
+    import_dir: PathBuf,
+) -> anyhow::Result<()> {
+    Server::new(bind, database_url, limits, http, spam, import_dir)
+        .start()
+        .await
 }
 
 async fn auth() -> anyhow::Result<()> {
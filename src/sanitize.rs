@@ -0,0 +1,168 @@
+use std::{
+    borrow::Cow,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use ammonia::{Builder, UrlRelative};
+
+/// Tags allowed on top of ammonia's defaults, for the table layouts and
+/// legacy formatting that mail clients still produce.
+const EXTRA_TAGS: &[&str] = &["font", "tfoot"];
+
+/// Presentational attributes allowed on any tag.
+const EXTRA_ATTRIBUTES: &[&str] = &[
+    "align",
+    "bgcolor",
+    "border",
+    "cellpadding",
+    "cellspacing",
+    "color",
+    "dir",
+    "face",
+    "height",
+    "size",
+    "style",
+    "valign",
+    "width",
+];
+
+/// An HTML body made safe to render in a browser.
+#[derive(Debug)]
+pub struct SanitizedHtml {
+    pub html: String,
+    /// Whether remote images or stylesheet URLs were removed.
+    pub remote_content_blocked: bool,
+}
+
+/// Sanitizes an email's HTML body so it can be served to browsers.
+///
+/// Scripts, forms, frames, event handlers and `<style>` blocks are removed,
+/// along with any attribute or URL scheme that isn't explicitly allowed.
+/// Unless `allow_remote_content` is set, images and CSS `url()`s that would
+/// load from a remote server, such as tracking pixels, are removed too.
+/// Embedded `data:` images and `cid:` references to attachments are kept.
+pub fn sanitize_html(html: &str, allow_remote_content: bool) -> SanitizedHtml {
+    let blocked = Arc::new(AtomicBool::new(false));
+    let filter_blocked = blocked.clone();
+
+    let html = Builder::default()
+        .add_tags(EXTRA_TAGS)
+        .add_generic_attributes(EXTRA_ATTRIBUTES)
+        .add_url_schemes(&["cid", "data"])
+        .url_relative(UrlRelative::Deny)
+        .attribute_filter(move |element, attribute, value| {
+            let remote = match (element, attribute) {
+                ("img", "src") => is_remote_url(value),
+                (_, "href") if starts_with_ignore_case(value, "data:") => return None,
+                (_, "style") => return filter_style(value, allow_remote_content, &filter_blocked),
+                _ => false,
+            };
+            if remote && !allow_remote_content {
+                filter_blocked.store(true, Ordering::Relaxed);
+                None
+            } else {
+                Some(Cow::Borrowed(value))
+            }
+        })
+        .clean(html)
+        .to_string();
+
+    SanitizedHtml {
+        html,
+        remote_content_blocked: blocked.load(Ordering::Relaxed),
+    }
+}
+
+fn starts_with_ignore_case(value: &str, prefix: &str) -> bool {
+    value
+        .get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+}
+
+/// Whether loading a URL would contact another server. Only embedded data
+/// and attachments of the message itself are local.
+fn is_remote_url(url: &str) -> bool {
+    let url = url.trim();
+    !starts_with_ignore_case(url, "data:") && !starts_with_ignore_case(url, "cid:")
+}
+
+/// Removes the declarations of an inline style that could run code or, unless
+/// allowed, load remote content.
+fn filter_style<'u>(
+    style: &'u str,
+    allow_remote_content: bool,
+    blocked: &AtomicBool,
+) -> Option<Cow<'u, str>> {
+    // CSS escapes could hide a `url(` from the checks below.
+    if style.contains('\\') {
+        return None;
+    }
+
+    let mut filtered = false;
+    let declarations: Vec<&str> = style
+        .split(';')
+        .filter(|declaration| {
+            let lower = declaration.to_ascii_lowercase();
+            let unsafe_ = lower.contains("expression(") || lower.contains("@import");
+            let remote = lower.match_indices("url(").any(|(start, _)| {
+                is_remote_url(lower[start + 4..].trim_start_matches(['"', '\'']))
+            }) || lower.contains("image-set(");
+            if remote && !allow_remote_content {
+                blocked.store(true, Ordering::Relaxed);
+            }
+            let keep = !unsafe_ && (allow_remote_content || !remote);
+            filtered |= !keep;
+            keep
+        })
+        .collect();
+
+    if filtered {
+        Some(Cow::Owned(declarations.join(";")))
+    } else {
+        Some(Cow::Borrowed(style))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_html() {
+        let html = r#"<p onclick="steal()">Hi<script>alert(1)</script></p><form action="https://evil.example"><input name="password"></form><a href="https://example.com">link</a>"#;
+        let sanitized = sanitize_html(html, false);
+        assert_eq!(
+            sanitized.html,
+            r#"<p>Hi</p><a href="https://example.com" rel="noopener noreferrer">link</a>"#
+        );
+        assert!(!sanitized.remote_content_blocked);
+
+        let html = r#"<img src="https://tracker.example/p.gif" width="1"><img src="cid:logo"><img src="data:image/png;base64,AAAA">"#;
+        let sanitized = sanitize_html(html, false);
+        assert_eq!(
+            sanitized.html,
+            r#"<img width="1"><img src="cid:logo"><img src="data:image/png;base64,AAAA">"#
+        );
+        assert!(sanitized.remote_content_blocked);
+
+        let sanitized = sanitize_html(html, true);
+        assert!(sanitized.html.contains("https://tracker.example/p.gif"));
+        assert!(!sanitized.remote_content_blocked);
+
+        let html =
+            r#"<td style="color: red; background: url('https://tracker.example/bg.png')">x</td>"#;
+        let sanitized = sanitize_html(&format!("<table><tr>{}</tr></table>", html), false);
+        assert!(sanitized.html.contains(r#"style="color: red""#));
+        assert!(sanitized.remote_content_blocked);
+
+        let sanitized = sanitize_html(
+            r#"<div style="background: url(data:image/png;base64,AAAA)">x</div>"#,
+            false,
+        );
+        assert!(sanitized.html.contains("url(data:image/png;base64,AAAA)"));
+        assert!(!sanitized.remote_content_blocked);
+    }
+}
@@ -0,0 +1,98 @@
+use std::{env, sync::Mutex, time::Instant};
+
+use postgres_queue::{TaskData, TaskError};
+use tokio::task::spawn_blocking;
+use tracing::info;
+
+use crate::{
+    auth::refresh_user_token,
+    database::{Database, FolderAlias, User},
+    graph::{EmailUpdate, GraphClient, HttpClient},
+    telemetry::record_job,
+};
+
+/// Hidden folder that snoozed emails wait in until they are due.
+pub const SNOOZED_FOLDER: &str = "Snoozed";
+
+/// Name of the queued task that brings a snoozed email back.
+pub const UNSNOOZE_TASK: &str = "unsnooze";
+
+pub async fn unsnooze_handler_sync(
+    http: HttpClient,
+    task_id: i32,
+    task_data: TaskData,
+) -> Result<(), TaskError> {
+    let start = Instant::now();
+    let fut = Mutex::new(Box::pin(unsnooze_handler(http, task_id, task_data)));
+    let result = match spawn_blocking(move || {
+        let mut guard = fut.lock().unwrap();
+        futures::executor::block_on(&mut *guard)
+    })
+    .await
+    {
+        Ok(result) => result,
+        Err(e) => Err(TaskError::Custom(e.to_string())),
+    };
+    record_job(UNSNOOZE_TASK, start.elapsed(), &result);
+    result
+}
+
+/// Moves a snoozed email back to the inbox, or the owner's `inbox` alias, and
+/// marks it unread. The owner's access token is refreshed first, since the
+/// one they registered has usually expired by the time the email is due.
+pub async fn unsnooze_handler(
+    http: HttpClient,
+    _task_id: i32,
+    task_data: TaskData,
+) -> Result<(), TaskError> {
+    let field = |name: &str| {
+        task_data[name]
+            .as_str()
+            .map(ToString::to_string)
+            .ok_or_else(|| TaskError::Custom(format!("missing {}", name)))
+    };
+    let user_email = field("user_email")?;
+    let email_id = field("email_id")?;
+    info!("Unsnoozing {email_id} for {user_email}");
+
+    let database_url = env::var("DATABASE_URL").map_err(|e| TaskError::Custom(e.to_string()))?;
+    let database = Database::new(database_url)
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))?;
+    let client = database
+        .get()
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))?;
+    let user = User::find(&client, &user_email)
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))?
+        .ok_or_else(|| TaskError::Custom(format!("unknown user {}", user_email)))?;
+    let token = refresh_user_token(&http, &client, &user)
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))?;
+
+    let aliases = FolderAlias::list_by_email(&client, &user_email)
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))?;
+
+    let mut graph = GraphClient::with_http_client(http, token)
+        .with_folder_aliases(aliases.into_iter().map(|alias| (alias.alias, alias.folder)));
+    let inbox_id = graph
+        .get_standard_folder_id("inbox")
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))?;
+    graph
+        .move_email_to_folder(&email_id, &inbox_id)
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))?;
+    let update = EmailUpdate {
+        is_read: Some(false),
+        flag_status: None,
+    };
+    graph
+        .update_email(&email_id, &update)
+        .await
+        .map_err(|e| TaskError::Custom(e.to_string()))?;
+
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use std::process::Stdio;
+
+use anyhow::{anyhow, Result};
+use tokio::{io::AsyncWriteExt, process::Command};
+
+/// Shell commands that train a spam filter with reported messages, such as
+/// `sa-learn --spam` or `rspamc learn_ham`. Each command receives the raw
+/// message on stdin.
+#[derive(Clone, Debug, Default)]
+pub struct SpamLearning {
+    pub spam_command: Option<String>,
+    pub ham_command: Option<String>,
+}
+
+impl SpamLearning {
+    pub fn command(&self, spam: bool) -> Option<&str> {
+        if spam {
+            self.spam_command.as_deref()
+        } else {
+            self.ham_command.as_deref()
+        }
+    }
+}
+
+/// Runs `command` through the shell, writing `input` to its stdin.
+pub async fn pipe_to_command(command: &str, input: &[u8]) -> Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input).await?;
+    }
+
+    let output = child.wait_with_output().await?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "`{}` failed with {}: {}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
@@ -0,0 +1,59 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use axum::{extract::MatchedPath, http::Request, middleware::Next, response::Response};
+use metrics::{counter, histogram};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder};
+use postgres_queue::TaskError;
+
+/// Histogram buckets, in seconds, for request and job durations.
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0,
+];
+
+fn builder() -> Result<PrometheusBuilder, BuildError> {
+    PrometheusBuilder::new().set_buckets_for_metric(
+        Matcher::Suffix("_duration_seconds".to_string()),
+        DURATION_BUCKETS,
+    )
+}
+
+/// Installs the Prometheus recorder, serving the metrics on their own
+/// listener at `addr`, apart from the API.
+pub fn install_listener(addr: SocketAddr) -> Result<(), BuildError> {
+    builder()?.with_http_listener(addr).install()
+}
+
+/// Records the count and duration of API requests by method, route and
+/// status. Routes are labelled by their pattern, such as `/api/emails/:id`,
+/// so ids don't create a series each.
+pub async fn track_requests<B>(request: Request<B>, next: Next<B>) -> Response {
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    let method = request.method().to_string();
+    let start = Instant::now();
+
+    let response = next.run(request).await;
+
+    if let Some(path) = path {
+        let labels = [
+            ("method", method),
+            ("path", path),
+            ("status", response.status().as_str().to_string()),
+        ];
+        counter!("http_requests_total", 1, &labels);
+        histogram!("http_request_duration_seconds", start.elapsed(), &labels);
+    }
+    response
+}
+
+/// Records the duration and outcome of a background job.
+pub fn record_job(name: &'static str, elapsed: Duration, result: &Result<(), TaskError>) {
+    let status = if result.is_ok() { "ok" } else { "error" };
+    counter!("jobs_total", 1, "job" => name, "status" => status);
+    histogram!("job_duration_seconds", elapsed, "job" => name);
+}
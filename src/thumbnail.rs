@@ -0,0 +1,136 @@
+use std::{
+    io::Cursor,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use image::{
+    io::{Limits, Reader},
+    ImageOutputFormat, ImageResult,
+};
+use lru::LruCache;
+
+/// Default width and height, in pixels, that thumbnails are fitted into.
+pub const DEFAULT_SIZE: u32 = 256;
+
+/// Largest thumbnail size a client can ask for.
+pub const MAX_SIZE: u32 = 1024;
+
+/// Largest width or height of an image that will be decoded, so a small file
+/// can't expand into a huge bitmap.
+const MAX_IMAGE_DIMENSION: u32 = 10_000;
+
+/// Most memory, in bytes, the decoder may allocate for a single image.
+const MAX_DECODE_ALLOC: u64 = 256 * 1024 * 1024;
+
+/// Number of thumbnails kept in memory.
+const CACHE_CAPACITY: usize = 512;
+
+/// An encoded thumbnail image.
+#[derive(Clone, Debug)]
+pub struct Thumbnail {
+    pub content_type: &'static str,
+    pub bytes: Bytes,
+}
+
+/// Whether attachments of a content type can be thumbnailed.
+pub fn is_supported(content_type: &str) -> bool {
+    matches!(
+        content_type.to_ascii_lowercase().as_str(),
+        "image/png" | "image/jpeg" | "image/jpg" | "image/gif" | "image/webp" | "image/bmp"
+    )
+}
+
+/// Scales an image down to fit within `size` by `size` pixels, keeping its
+/// aspect ratio. Smaller images keep their size. Images with transparency
+/// are encoded as PNG, anything else as JPEG.
+pub fn render(image: &[u8], size: u32) -> ImageResult<Thumbnail> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+
+    let mut reader = Reader::new(Cursor::new(image)).with_guessed_format()?;
+    reader.limits(limits);
+    let image = reader.decode()?;
+    let thumbnail = if image.width() > size || image.height() > size {
+        image.thumbnail(size, size)
+    } else {
+        image
+    };
+
+    let mut bytes = Cursor::new(Vec::new());
+    let content_type = if thumbnail.color().has_alpha() {
+        thumbnail.write_to(&mut bytes, ImageOutputFormat::Png)?;
+        "image/png"
+    } else {
+        thumbnail
+            .to_rgb8()
+            .write_to(&mut bytes, ImageOutputFormat::Jpeg(80))?;
+        "image/jpeg"
+    };
+
+    Ok(Thumbnail {
+        content_type,
+        bytes: bytes.into_inner().into(),
+    })
+}
+
+/// Message id, attachment id and size of a cached thumbnail.
+type CacheKey = (String, String, u32);
+
+/// In-memory cache of rendered thumbnails, keyed by message id, attachment id
+/// and size. Callers must check that the user can read the attachment before
+/// looking it up.
+#[derive(Clone)]
+pub struct ThumbnailCache(Arc<Mutex<LruCache<CacheKey, Thumbnail>>>);
+
+impl Default for ThumbnailCache {
+    fn default() -> Self {
+        let capacity = NonZeroUsize::new(CACHE_CAPACITY).unwrap();
+        Self(Arc::new(Mutex::new(LruCache::new(capacity))))
+    }
+}
+
+impl ThumbnailCache {
+    pub fn get(&self, email_id: &str, attachment_id: &str, size: u32) -> Option<Thumbnail> {
+        let key = (email_id.to_string(), attachment_id.to_string(), size);
+        self.0.lock().unwrap().get(&key).cloned()
+    }
+
+    pub fn put(&self, email_id: &str, attachment_id: &str, size: u32, thumbnail: Thumbnail) {
+        let key = (email_id.to_string(), attachment_id.to_string(), size);
+        self.0.lock().unwrap().put(key, thumbnail);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView, RgbImage, RgbaImage};
+
+    use super::*;
+
+    fn encode_png(image: image::DynamicImage) -> Vec<u8> {
+        let mut bytes = Cursor::new(Vec::new());
+        image.write_to(&mut bytes, ImageOutputFormat::Png).unwrap();
+        bytes.into_inner()
+    }
+
+    #[test]
+    fn test_render() {
+        let png = encode_png(RgbImage::new(400, 200).into());
+        let thumbnail = render(&png, 100).unwrap();
+        assert_eq!(thumbnail.content_type, "image/jpeg");
+        let decoded = image::load_from_memory(&thumbnail.bytes).unwrap();
+        assert_eq!(decoded.dimensions(), (100, 50));
+
+        let png = encode_png(RgbaImage::new(50, 80).into());
+        let thumbnail = render(&png, 100).unwrap();
+        assert_eq!(thumbnail.content_type, "image/png");
+        let decoded = image::load_from_memory(&thumbnail.bytes).unwrap();
+        assert_eq!(decoded.dimensions(), (50, 80));
+
+        assert!(render(b"not an image", 100).is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use anyhow::{anyhow, Result};
 
 pub fn get_payload(token: &str) -> Result<serde_json::Value> {
-    let str = token.split('.').nth(1).unwrap();
+    let str = token.split('.').nth(1).ok_or(anyhow!("invalid token"))?;
     let decoded = base64::decode_config(str, base64::URL_SAFE_NO_PAD)?;
     let json = String::from_utf8(decoded)?;
     let value: serde_json::Value = serde_json::from_str(&json)?;
@@ -11,5 +11,6 @@ pub fn get_payload(token: &str) -> Result<serde_json::Value> {
 pub fn get_payload_field(token: &str, field: &str) -> Result<String> {
     let value = get_payload(token)?;
     let field = value.get(field).ok_or(anyhow!("invalid token"))?;
-    Ok(field.as_str().unwrap().to_string())
+    let field = field.as_str().ok_or(anyhow!("invalid token"))?;
+    Ok(field.to_string())
 }